@@ -0,0 +1,138 @@
+//! Benchmarks for building and rendering `Code`.
+
+#[macro_use]
+extern crate criterion;
+#[macro_use]
+extern crate cghelper;
+
+use cghelper::{Code, CodeArena, Fragment};
+use criterion::{black_box, BenchmarkId, Criterion};
+
+fn field(name: &str, ty: &str) -> Code {
+    code!("
+        /// The `$name` field.
+        $ty $name;
+        ",
+        name: name,
+        ty: ty,
+    )
+}
+
+/// Wrap `inner` in `depth` nested blocks.
+fn nested(depth: usize, inner: Code) -> Code {
+    (0..depth).fold(inner, |inner, i| {
+        code!("
+            if (x > $i) {
+                $inner
+            }",
+            i: i,
+            inner: inner,
+        )
+    })
+}
+
+/// A list of `n` structs, each with 10 fields.
+fn structs(n: usize) -> Code {
+    (0..n).map(|i| {
+        let fields: Code = (0..10).map(|j| {
+            field(&format!("field{}", j), "uint32_t")
+        }).collect();
+        code!("
+            struct S$i {
+                $fields
+            };
+            ",
+            i: i,
+            fields: fields,
+        )
+    }).collect()
+}
+
+/// The same as `structs`, but built within `arena`.
+fn arena_structs(arena: &CodeArena, n: usize) -> Fragment<'_> {
+    (0..n).fold(code_in!(arena, ""), |acc, i| {
+        let fields = (0..10).fold(code_in!(arena, ""), |acc, j| {
+            code_in!(arena, "
+                $acc
+                /// The `field$j` field.
+                uint32_t field$j;
+                ",
+                acc: acc,
+                j: j,
+            )
+        });
+        code_in!(arena, "
+            $acc
+            struct S$i {
+                $fields
+            };
+            ",
+            acc: acc,
+            i: i,
+            fields: fields,
+        )
+    })
+}
+
+fn build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build");
+    group.bench_function("code", |b| {
+        b.iter(|| field(black_box("name"), black_box("uint32_t")))
+    });
+    for &depth in &[10, 100] {
+        group.bench_with_input(
+            BenchmarkId::new("nested", depth),
+            &depth,
+            |b, &depth| b.iter(|| nested(depth, code!("return;"))),
+        );
+    }
+    for &n in &[10, 1000] {
+        group.bench_with_input(
+            BenchmarkId::new("collect", n),
+            &n,
+            |b, &n| b.iter(|| {
+                (0..n).map(|i| code!("case $i:", i: i)).collect::<Code>()
+            }),
+        );
+    }
+    group.finish();
+}
+
+fn display(c: &mut Criterion) {
+    let mut group = c.benchmark_group("display");
+    let deep = nested(100, code!("return;"));
+    group.bench_function("nested/100", |b| b.iter(|| deep.to_string()));
+    for &n in &[10, 1000] {
+        let code = structs(n);
+        group.bench_with_input(
+            BenchmarkId::new("structs", n),
+            &code,
+            |b, code| b.iter(|| code.to_string()),
+        );
+    }
+    group.finish();
+}
+
+fn arena(c: &mut Criterion) {
+    let mut group = c.benchmark_group("arena");
+    for &n in &[10, 1000] {
+        group.bench_with_input(
+            BenchmarkId::new("heap", n),
+            &n,
+            |b, &n| b.iter(|| structs(n).to_string()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("arena", n),
+            &n,
+            |b, &n| b.iter(|| {
+                let arena = CodeArena::new();
+                let code = arena_structs(&arena, n);
+                code.to_string()
+            }),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, build, display, arena);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+//! Compares building a `code!` template with and without the per-call-site
+//! template cache.
+
+#[macro_use]
+extern crate criterion;
+extern crate cghelper;
+
+use cghelper::{BuildArg, Code, SourceLoc, TemplateCache};
+use criterion::{black_box, Criterion};
+
+const TMPL: &str = "
+    /// Accessor for the `$name` field.
+    $ty get_$name() const {
+        return m$name;
+    }
+
+    /// Setter for the `$name` field.
+    void set_$name($ty value) {
+        m$name = value;
+    }
+";
+
+static CACHED: SourceLoc = SourceLoc::new(line!(), column!(), file!());
+
+static UNCACHED: SourceLoc = SourceLoc {
+    line: line!(),
+    column: column!(),
+    file: file!(),
+    template: TemplateCache::disabled(),
+};
+
+fn build(loc: &'static SourceLoc, i: usize) -> Code {
+    Code::build(TMPL, loc, &mut [
+        BuildArg::new("name", black_box("field")),
+        BuildArg::new("ty", black_box(i)),
+    ])
+}
+
+fn template_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("template_cache");
+    group.bench_function("cached", |b| b.iter(|| build(&CACHED, 5)));
+    group.bench_function("uncached", |b| b.iter(|| build(&UNCACHED, 5)));
+    group.finish();
+}
+
+criterion_group!(benches, template_cache);
+criterion_main!(benches);
@@ -0,0 +1,287 @@
+//! This module provides `CodeArena`, which stores the text and structure of
+//! many fragments of code in a small number of large buffers.
+
+use {BuildArg, Code, CodeArg, Op, SourceLoc};
+use display;
+use filter::Filters;
+use render::Highlight;
+use template::{self, Piece};
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+#[cfg(test)]
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::ptr;
+
+/// The buffers backing a `CodeArena`. `Op::ArenaText` and `Op::ArenaInner`
+/// are ranges of `text` and `ops` respectively.
+pub(crate) struct ArenaData {
+    pub(crate) text: String,
+    pub(crate) ops: Vec<Op>,
+}
+
+// Not a public API
+#[doc(hidden)]
+pub struct ArenaOp(pub(crate) Op);
+
+/// An arena from which [`Fragment`]s of code can be built, using the
+/// [`code_in!`] macro.
+///
+/// Building large amounts of code with [`code!`] performs a heap allocation
+/// for each fragment, and each chunk of dynamic text within it. Fragments built
+/// in a `CodeArena` instead store their text and structure in buffers shared by
+/// the whole arena, which are freed all at once when the arena is dropped.
+///
+/// Fragments may be used as arguments to `code_in!` for the same arena, in
+/// which case they are referenced rather than copied. They may also be used as
+/// arguments to `code!`, or to `code_in!` for another arena, in which case they
+/// are copied out of the arena.
+///
+/// Substitutions in templates passed to `code_in!` may use the filters set
+/// with [`set_filters`], as well as the built-in ones.
+///
+/// [`Fragment`]: struct.Fragment.html
+/// [`code_in!`]: macro.code_in.html
+/// [`code!`]: macro.code.html
+/// [`set_filters`]: #method.set_filters
+pub struct CodeArena {
+    data: RefCell<ArenaData>,
+    filters: Filters,
+}
+
+impl CodeArena {
+    /// Create a new, empty, `CodeArena`.
+    pub fn new() -> Self {
+        CodeArena::with_capacity(0, 0)
+    }
+
+    /// Create a new `CodeArena`, pre-allocating space for `text` bytes of
+    /// dynamic text, and `ops` pieces of code structure.
+    pub fn with_capacity(text: usize, ops: usize) -> Self {
+        CodeArena {
+            data: RefCell::new(ArenaData {
+                text: String::with_capacity(text),
+                ops: Vec::with_capacity(ops),
+            }),
+            filters: Filters::new(),
+        }
+    }
+
+    /// Set the filters available to substitutions such as `${name|filter}`
+    /// in templates passed to `code_in!` for this arena.
+    pub fn set_filters(&mut self, filters: Filters) {
+        self.filters = filters;
+    }
+
+    // Not a public API - use code_in! instead.
+    #[doc(hidden)]
+    pub fn build(
+        &self,
+        tmpl: &'static str,
+        sourceloc: &'static SourceLoc,
+        args: &mut [BuildArg],
+    ) -> Fragment<'_> {
+        let data = &mut *self.data.borrow_mut();
+        let start = data.ops.len();
+        sourceloc.template.build(
+            tmpl, sourceloc, args, &mut data.ops, &data.text, &self.filters,
+        );
+        Fragment {
+            arena: self,
+            start,
+            end: data.ops.len(),
+        }
+    }
+
+    /// Copy the text of a value with no newlines into the arena.
+    pub(crate) fn push_display<T: fmt::Display>(&self, v: T) -> Op {
+        let text = &mut self.data.borrow_mut().text;
+        let start = text.len();
+        write!(text, "{}", v)
+            .expect("a Display implementation returned an error");
+        Op::ArenaText(start, text.len())
+    }
+
+    /// Copy a string into the arena, with the same transformations as are
+    /// performed on `&str` arguments to `code!`. Common indentation is only
+    /// removed if `dedent` is set.
+    pub(crate) fn push_str(&self, s: &str, dedent: bool) -> Op {
+        let pieces = template::parse_str(s, dedent);
+        if let [Piece::Text(seg)] = pieces[..] {
+            return self.push_display(seg);
+        }
+
+        let data = &mut *self.data.borrow_mut();
+        let start = data.ops.len();
+        for piece in pieces {
+            let op = match piece {
+                Piece::Nl => Op::Nl,
+                Piece::Text(seg) => {
+                    let text_start = data.text.len();
+                    data.text.push_str(seg);
+                    Op::ArenaText(text_start, data.text.len())
+                }
+                Piece::Arg(..) | Piece::Raw(..) => {
+                    unreachable!("directive without arguments")
+                }
+            };
+            data.ops.push(op);
+        }
+        Op::ArenaInner(start, data.ops.len())
+    }
+}
+
+impl Default for CodeArena {
+    fn default() -> Self {
+        CodeArena::new()
+    }
+}
+
+/// A chunk of code stored within a [`CodeArena`].
+///
+/// Like [`Code`], the `Display` implementation renders the code, and the
+/// alternate `Debug` implementation is colorized by source location.
+///
+/// [`CodeArena`]: struct.CodeArena.html
+/// [`Code`]: struct.Code.html
+#[derive(Clone, Copy)]
+pub struct Fragment<'a> {
+    arena: &'a CodeArena,
+    start: usize,
+    end: usize,
+}
+
+/// Copy ops out of an arena with the given `text` and `arena_ops`, so that
+/// they no longer reference it.
+pub(crate) fn export(text: &str, arena_ops: &[Op], ops: &[Op]) -> Vec<Op> {
+    ops.iter().map(|op| match *op {
+        Op::ArenaText(start, end) => {
+            Op::Blob(text[start..end].to_owned().into_boxed_str())
+        }
+        Op::ArenaInner(start, end) => {
+            let inner = export(text, arena_ops, &arena_ops[start..end]);
+            Op::Inner(Arc::new(inner))
+        }
+        ref op => op.clone(),
+    }).collect()
+}
+
+impl<'a> CodeArg for Fragment<'a> {
+    fn into_code(self) -> Code {
+        let data = self.arena.data.borrow();
+        let ops = &data.ops[self.start..self.end];
+        Code::from_ops(export(&data.text, &data.ops, ops))
+    }
+
+    fn into_arena(self, arena: &CodeArena) -> ArenaOp {
+        if ptr::eq(self.arena, arena) {
+            ArenaOp(Op::ArenaInner(self.start, self.end))
+        } else {
+            ArenaOp(Op::Inner(self.into_code().ops))
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Fragment<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let data = self.arena.data.borrow();
+        let ops = &data.ops[self.start..self.end];
+        if f.alternate() {
+            f.write_str("Fragment {\n")?;
+            display::do_display(ops, Some(&data), f, 4, Highlight::Auto)?;
+            f.write_str("\n}")
+        } else {
+            f.write_str("Fragment {")?;
+            display::do_display(ops, Some(&data), f, 0, Highlight::None)?;
+            f.write_str("}")
+        }
+    }
+}
+
+impl<'a> fmt::Display for Fragment<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let data = self.arena.data.borrow();
+        let ops = &data.ops[self.start..self.end];
+        display::do_display(ops, Some(&data), f, 0, Highlight::None)
+    }
+}
+
+#[test]
+fn arena_test() {
+    let fields = [("a", "uint32_t"), ("b", "char*")];
+    let field_names = vec!["x".to_owned(), "y".to_owned()];
+
+    let arena = CodeArena::new();
+    let body = fields.iter().fold(code_in!(&arena, ""), |acc, &(name, ty)| {
+        code_in!(&arena, "
+            $acc
+            $ty $name;",
+            acc: acc,
+            ty: ty,
+            name: name,
+        )
+    });
+    let res = code_in!(&arena, "
+        struct $name {
+            $body
+        } $names;",
+        name: "S",
+        body: body,
+        names: ::Joined::new(field_names, ", "),
+    );
+
+    let expected = "struct S {\n    uint32_t a;\n    char* b;\n} x, y;";
+    assert_eq!(res.to_string(), expected);
+
+    // Copying the fragment out of the arena produces the same code.
+    let code = code!("$res", res: res);
+    drop(arena);
+    assert_eq!(code.to_string(), expected);
+}
+
+#[test]
+fn arena_reused_args() {
+    let arena = CodeArena::new();
+    let frag = code_in!(&arena, "f()");
+    let owned = String::from("ref");
+    let res = code_in!(&arena, "
+        $b $b
+        $i $i
+        $s $s
+        $o $o
+        $r $r
+        $k $k
+        $t $t
+        $f $f
+        $m $m",
+        b: true,
+        i: 7u8,
+        s: "str",
+        o: String::from("owned"),
+        r: &owned,
+        k: ::KeepIndent(" k"),
+        t: ::TrimIndent(" t"),
+        f: frag,
+        m: "x\ny",
+    );
+    assert_eq!(
+        res.to_string(),
+        "true true\n7 7\nstr str\nowned owned\nref ref\n k  k\nt t\n\
+         f() f()\nx\ny x\n  y"
+    );
+
+    // The repeated arguments are still valid once copied out of the arena.
+    let rep = code_in!(&arena, "$i + $i, $s $s, $b $b", i: 5, s: "x", b: true);
+    let copied = code!("[$rep]", rep);
+    assert_eq!(copied.to_string(), "[5 + 5, x x, true true]");
+    assert!(copied == code!("[$rep]", rep));
+    assert_eq!(copied.lines().count(), 1);
+    assert_eq!(code!("${rep|upper}", rep).to_string(), "5 + 5, X X, TRUE TRUE");
+    let other = CodeArena::new();
+    let copied = code_in!(&other, "$rep", rep);
+    assert_eq!(copied.to_string(), "5 + 5, x x, true true");
+}
@@ -0,0 +1,128 @@
+//! This module provides `BlankLines`, which controls how runs of blank lines
+//! within a fragment of code are collapsed.
+
+use {Code, Op};
+
+/// A policy for the blank lines within a fragment of [`Code`], applied with
+/// [`Code::blank_lines`].
+///
+/// By default, runs of blank lines are collapsed into a single blank line, and
+/// blank lines after an opening brace or before a closing brace are removed.
+/// Within a fragment with a `BlankLines` policy, each run of blank lines is
+/// instead kept as written, but clamped to between `min` and `max` lines, and
+/// braces are not treated specially. Lines which are not separated by a blank
+/// line are unaffected.
+///
+/// Nested fragments use the policy of the fragment they are substituted into,
+/// unless they have a policy of their own.
+///
+/// [`Code`]: struct.Code.html
+/// [`Code::blank_lines`]: struct.Code.html#method.blank_lines
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::BlankLines;
+/// # fn main() {
+/// let migration = code!("
+///     CREATE TABLE a (
+///
+///         id INTEGER
+///
+///     );
+///
+///
+///
+///     CREATE TABLE b ();");
+///
+/// let res = migration.blank_lines(BlankLines::new().max(2));
+/// assert_eq!(
+///     res.to_string(),
+///     "CREATE TABLE a (\n\n    id INTEGER\n\n);\n\n\nCREATE TABLE b ();",
+/// );
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlankLines {
+    min: usize,
+    max: Option<usize>,
+}
+
+impl BlankLines {
+    /// A policy which keeps every blank line as written.
+    pub fn new() -> Self {
+        BlankLines {
+            min: 0,
+            max: None,
+        }
+    }
+
+    /// Expand each run of blank lines to at least `min` lines.
+    pub fn min(mut self, min: usize) -> Self {
+        self.min = min;
+        self
+    }
+
+    /// Collapse each run of blank lines to at most `max` lines.
+    pub fn max(mut self, max: usize) -> Self {
+        self.max = Some(max);
+        self
+    }
+
+    /// Clamp a run of `nls` consecutive newlines, which includes the newline
+    /// ending the previous line.
+    pub(crate) fn clamp(&self, nls: usize) -> usize {
+        if nls <= 1 {
+            return nls;
+        }
+        let nls = usize::max(nls, self.min + 1);
+        match self.max {
+            Some(max) => usize::min(nls, max + 1),
+            None => nls,
+        }
+    }
+}
+
+impl Default for BlankLines {
+    fn default() -> Self {
+        BlankLines::new()
+    }
+}
+
+impl Code {
+    /// Apply a [`BlankLines`] policy to the blank lines within this code.
+    ///
+    /// [`BlankLines`]: struct.BlankLines.html
+    pub fn blank_lines(&self, policy: BlankLines) -> Code {
+        Code::from_ops(vec![ Op::BlankLines(policy, self.ops.clone()) ])
+    }
+}
+
+#[test]
+fn blank_lines_test() {
+    use alloc::string::ToString;
+
+    let body = code!("
+        a;
+
+
+        b;");
+    let res = code!("
+        {
+
+            $kept
+
+            $default
+
+        }",
+        kept: body.blank_lines(BlankLines::new()),
+        default: body,
+    );
+    assert_eq!(res.to_string(), "{\n    a;\n\n\n    b;\n\n    a;\n\n    b;\n}");
+
+    let spaced = code!("a;\nb;\n\nc;").blank_lines(BlankLines::new().min(2));
+    assert_eq!(spaced.to_string(), "a;\nb;\n\n\nc;");
+    let dense = code!("{\n\na;\n\n}").blank_lines(BlankLines::new().max(0));
+    assert_eq!(dense.to_string(), "{\na;\n}");
+}
@@ -1,4 +1,14 @@
-use {Code, Op, str_to_code};
+use {Code, CodeArena, FmtValue, Op};
+use arena::ArenaOp;
+use spec::float_literal;
+use template::str_to_code;
+
+use alloc::borrow::{Cow, ToOwned};
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 /// Objects which implement this trait can be converted into [`Code`] objects.
 /// This allows them to be used as arguments to the [`code!`] macro.
@@ -8,6 +18,21 @@ use {Code, Op, str_to_code};
 pub trait CodeArg {
     /// Convert this object into a `Code` object.
     fn into_code(self) -> Code;
+
+    // Not a public API - allows types to write directly into a `CodeArena`.
+    #[doc(hidden)]
+    fn into_arena(self, _arena: &CodeArena) -> ArenaOp
+    where
+        Self: Sized,
+    {
+        ArenaOp(Op::Inner(self.into_code().ops))
+    }
+
+    // Not a public API - allows numbers to be formatted with a format spec.
+    #[doc(hidden)]
+    fn fmt_value(&self) -> Option<FmtValue> {
+        None
+    }
 }
 
 impl CodeArg for Code {
@@ -16,48 +41,411 @@ impl CodeArg for Code {
     }
 }
 
-impl CodeArg for bool {
+impl<T: CodeArg> CodeArg for Option<T> {
     fn into_code(self) -> Code {
-        Code {
-             ops: vec![ Op::Lit(if self { "true" } else { "false" }) ]
+        match self {
+            Some(x) => x.into_code(),
+            None => Code::new(),
+        }
+    }
+
+    fn into_arena(self, arena: &CodeArena) -> ArenaOp {
+        match self {
+            Some(x) => x.into_arena(arena),
+            None => ArenaOp(Op::Inner(Code::new().ops)),
         }
     }
+
+    fn fmt_value(&self) -> Option<FmtValue> {
+        self.as_ref().and_then(|x| x.fmt_value())
+    }
+}
+
+impl<T: CodeArg> CodeArg for Vec<T> {
+    fn into_code(self) -> Code {
+        self.into_iter().collect()
+    }
+}
+
+impl<T: CodeArg + Clone> CodeArg for &[T] {
+    fn into_code(self) -> Code {
+        self.iter().cloned().collect()
+    }
+}
+
+impl CodeArg for bool {
+    fn into_code(self) -> Code {
+        Code::from_ops(vec![ Op::Lit(if self { "true" } else { "false" }) ])
+    }
+
+    fn into_arena(self, arena: &CodeArena) -> ArenaOp {
+        // NOTE: Arguments used more than once are referenced by `InnerRef`,
+        // which can't refer to a bare `Lit`.
+        ArenaOp(arena.push_display(self))
+    }
+}
+
+/// Check whether `s` is a single line which `str_to_code` would leave as is.
+fn is_plain_line(s: &str) -> bool {
+    !s.is_empty() &&
+        !s.contains('\n') &&
+        !s.starts_with(char::is_whitespace) &&
+        !s.ends_with(char::is_whitespace)
 }
 
 impl CodeArg for String {
     fn into_code(self) -> Code {
-        if !self.contains('\n') && self.chars().map(char::is_whitespace).next().unwrap_or(false) {
+        if is_plain_line(&self) {
             // We won't be performing any transformations on this buffer, so
             // let's just save the string directly, saving allocations.
-            Code {
-                ops: vec![ Op::Blob(self.into_boxed_str()) ],
-            }
+            Code::from_ops(vec![ Op::Blob(self.into_boxed_str()) ])
         } else {
             (&self[..]).into_code()
         }
     }
+
+    fn into_arena(self, arena: &CodeArena) -> ArenaOp {
+        (&self[..]).into_arena(arena)
+    }
 }
 
-impl<'a> CodeArg for &'a str {
+/// Strings are split into lines, and trailing whitespace is removed from each
+/// line. The common indentation of the non-blank lines is removed, so a string
+/// with a single line has its leading whitespace removed too. Lines after the
+/// first are indented to match the column at which the string is substituted.
+///
+/// The same rules apply to every string type, including `String`. Use
+/// [`KeepIndent`] to keep the indentation of each line, or [`Raw`] to emit the
+/// string exactly as written.
+///
+/// [`KeepIndent`]: struct.KeepIndent.html
+/// [`Raw`]: struct.Raw.html
+impl CodeArg for &str {
     fn into_code(self) -> Code {
-        str_to_code(
-            self,
-            None,
-            None,
-            |s| Op::Blob(s.to_owned().into_boxed_str()),
-        )
+        str_to_code(self, true, |s| Op::Blob(s.to_owned().into_boxed_str()))
+    }
+
+    fn into_arena(self, arena: &CodeArena) -> ArenaOp {
+        ArenaOp(arena.push_str(self, true))
     }
 }
 
-macro_rules! codearg_display {($($i:ident),*) => {
-    $( impl CodeArg for $i {
+impl CodeArg for &String {
+    fn into_code(self) -> Code {
+        (&self[..]).into_code()
+    }
+
+    fn into_arena(self, arena: &CodeArena) -> ArenaOp {
+        (&self[..]).into_arena(arena)
+    }
+}
+
+impl CodeArg for Box<str> {
+    fn into_code(self) -> Code {
+        self.into_string().into_code()
+    }
+}
+
+impl CodeArg for Rc<str> {
+    fn into_code(self) -> Code {
+        (&self[..]).into_code()
+    }
+}
+
+impl CodeArg for Arc<str> {
+    fn into_code(self) -> Code {
+        (&self[..]).into_code()
+    }
+}
+
+impl CodeArg for Cow<'_, str> {
+    fn into_code(self) -> Code {
+        match self {
+            Cow::Borrowed(s) => s.into_code(),
+            Cow::Owned(s) => s.into_code(),
+        }
+    }
+}
+
+impl CodeArg for char {
+    fn into_code(self) -> Code {
+        let op = match self {
+            '\n' => Op::Nl,
+            c => Op::Blob(c.to_string().into_boxed_str()),
+        };
+        Code::from_ops(vec![ op ])
+    }
+}
+
+macro_rules! codearg_display {($($v:ident: $($i:ident),*;)*) => {
+    $($( impl CodeArg for $i {
         fn into_code(self) -> Code {
             // We know that the strings won't contain '\n' or any leading
             // whitespace, so we can skip that test.
-            Code {
-                ops: vec![ Op::Blob(self.to_string().into_boxed_str()) ],
-            }
+            Code::from_ops(vec![ Op::Blob(self.to_string().into_boxed_str()) ])
+        }
+
+        fn into_arena(self, arena: &CodeArena) -> ArenaOp {
+            ArenaOp(arena.push_display(self))
+        }
+
+        fn fmt_value(&self) -> Option<FmtValue> {
+            Some(FmtValue::$v(*self as _, $i::BITS))
+        }
+    } )*)*
+}}
+codearg_display! {
+    Signed: i8, i16, i32, i64, i128, isize;
+    Unsigned: u8, u16, u32, u64, u128, usize;
+}
+
+macro_rules! codearg_float {($($i:ident),*) => {
+    $( impl CodeArg for $i {
+        fn into_code(self) -> Code {
+            let single = core::mem::size_of::<$i>() == 4;
+            let s = float_literal(self as f64, single);
+            Code::from_ops(vec![ Op::Blob(s.into_boxed_str()) ])
+        }
+
+        fn fmt_value(&self) -> Option<FmtValue> {
+            let single = core::mem::size_of::<$i>() == 4;
+            Some(FmtValue::Float(*self as f64, single))
         }
     } )*
 }}
-codearg_display! { i8, i16, i32, i64, u8, u16, u32, u64, f32, f64 }
+codearg_float! { f32, f64 }
+
+/// A [`CodeArg`] which concatenates each item produced by an iterator, placing
+/// a separator between consecutive items.
+///
+/// Unlike a `&str` argument, the separator is emitted exactly as written, so
+/// leading and trailing whitespace is preserved. A `\n` within the separator
+/// starts a new line at the current indentation.
+///
+/// [`CodeArg`]: trait.CodeArg.html
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::Joined;
+/// # fn main() {
+/// let args = vec!["int a", "char* b"];
+/// let res = code!("void f($args);", args: Joined::new(args, ", "));
+/// assert_eq!(res.to_string(), "void f(int a, char* b);");
+/// # }
+/// ```
+pub struct Joined<I> {
+    iter: I,
+    sep: &'static str,
+}
+
+impl<I> Joined<I>
+where
+    I: IntoIterator,
+    I::Item: CodeArg,
+{
+    /// Join the items of `iter`, with `sep` between each pair of items.
+    pub fn new(iter: I, sep: &'static str) -> Self {
+        Joined { iter, sep }
+    }
+}
+
+impl<I> CodeArg for Joined<I>
+where
+    I: IntoIterator,
+    I::Item: CodeArg,
+{
+    fn into_code(self) -> Code {
+        let mut c = Code::new();
+        for (idx, x) in self.iter.into_iter().enumerate() {
+            if idx != 0 {
+                for (idx, seg) in self.sep.split('\n').enumerate() {
+                    if idx != 0 {
+                        c.ops_mut().push(Op::Nl);
+                    }
+                    if !seg.is_empty() {
+                        c.ops_mut().push(Op::Lit(seg));
+                    }
+                }
+            }
+            c.push(x);
+        }
+        c
+    }
+}
+
+/// A [`CodeArg`] which emits a string exactly as written.
+///
+/// Unlike a `&str` argument, common indentation and trailing whitespace are
+/// not removed, blank and whitespace-only lines are preserved, and lines after
+/// the first are not indented to match the surrounding code. This is useful
+/// for heredocs, Markdown, and patches.
+///
+/// Text within a `code!` template can be emitted exactly as written by placing
+/// it between `${raw}` and `${endraw}`.
+///
+/// [`CodeArg`]: trait.CodeArg.html
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::Raw;
+/// # fn main() {
+/// let md = "Title  \nline\n\n\n  indented";
+/// let res = code!("
+///     /*
+///         $md
+///     */",
+///     md: Raw(md),
+/// );
+/// assert_eq!(res.to_string(), "/*\n    Title  \nline\n\n\n  indented\n*/");
+///
+/// // Substitutions are not performed within `${raw}` sections.
+/// let res = code!("
+///     cat <<'EOF'
+///     ${raw}  $HOME is kept
+/// EOF${endraw}
+///     done");
+/// assert_eq!(res.to_string(), "cat <<'EOF'\n  $HOME is kept\nEOF\ndone");
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Raw<S>(pub S);
+
+impl<S: AsRef<str>> CodeArg for Raw<S> {
+    fn into_code(self) -> Code {
+        let s = self.0.as_ref();
+        if s.is_empty() {
+            return Code::new();
+        }
+        Code::from_ops(vec![ Op::RawBlob(s.into()) ])
+    }
+}
+
+/// A [`CodeArg`] which removes the common indentation of a string, as is done
+/// for `&str` arguments.
+///
+/// This is useful for string types which don't implement `CodeArg`
+/// themselves, and to make the choice explicit alongside [`KeepIndent`].
+///
+/// [`CodeArg`]: trait.CodeArg.html
+/// [`KeepIndent`]: struct.KeepIndent.html
+#[derive(Debug, Clone, Copy)]
+pub struct TrimIndent<S>(pub S);
+
+impl<S: AsRef<str>> CodeArg for TrimIndent<S> {
+    fn into_code(self) -> Code {
+        self.0.as_ref().into_code()
+    }
+
+    fn into_arena(self, arena: &CodeArena) -> ArenaOp {
+        ArenaOp(arena.push_str(self.0.as_ref(), true))
+    }
+}
+
+/// A [`CodeArg`] which keeps the indentation of each line of a string.
+///
+/// Like a `&str` argument, the string is split into lines, trailing whitespace
+/// is removed, and lines after the first are indented to match the column at
+/// which it is substituted. However, no common indentation is removed, so
+/// leading whitespace is kept on every line, including the first.
+///
+/// [`CodeArg`]: trait.CodeArg.html
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::{KeepIndent, TrimIndent};
+/// # fn main() {
+/// let body = "  a\n    b";
+/// let res = code!("{ $x }", x: TrimIndent(body));
+/// assert_eq!(res.to_string(), "{ a\n    b }");
+/// let res = code!("{ $x }", x: KeepIndent(body));
+/// assert_eq!(res.to_string(), "{   a\n      b }");
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct KeepIndent<S>(pub S);
+
+impl<S: AsRef<str>> CodeArg for KeepIndent<S> {
+    fn into_code(self) -> Code {
+        str_to_code(self.0.as_ref(), false, |s| {
+            Op::Blob(s.to_owned().into_boxed_str())
+        })
+    }
+
+    fn into_arena(self, arena: &CodeArena) -> ArenaOp {
+        ArenaOp(arena.push_str(self.0.as_ref(), false))
+    }
+}
+
+#[test]
+fn container_args() {
+    let none: Option<&str> = None;
+    let res = code!("a$x b$y", x: none, y: Some("1"));
+    assert_eq!(res.to_string(), "a b1");
+
+    // Optional numbers can still be formatted with a format spec.
+    let res = code!("[${x:04}] [${y:#x}]", x: Some(5), y: Some(255u8));
+    assert_eq!(res.to_string(), "[0005] [0xff]");
+    let arena = ::CodeArena::new();
+    let res = code_in!(&arena, "$x $x ${x:>3}", x: Some(7));
+    assert_eq!(res.to_string(), "7 7   7");
+
+    let items = ["x", "y", "z"];
+    let res = code!("$v $s", v: vec![1, 2, 3], s: &items[..]);
+    assert_eq!(res.to_string(), "123 xyz");
+
+    let res = code!("
+        {
+            $fields
+        }",
+        fields: Joined::new(items.iter().cloned(), ",\n"),
+    );
+    assert_eq!(res.to_string(), "{\n    x,\n    y,\n    z\n}");
+}
+
+#[test]
+fn scalar_args() {
+    let res = code!("$a $b $c $d", a: 1usize, b: -2isize, c: u128::MAX, d: 'x');
+    assert_eq!(res.to_string(), "1 -2 340282366920938463463374607431768211455 x");
+
+    let res = code!("$a $b $c $d", a: 1.0f64, b: 0.5f32, c: 1e300, d: -0.0);
+    assert_eq!(res.to_string(), "1.0 0.5 1e300 -0.0");
+
+    let res = code!("$a $b $c", a: f64::NAN, b: f32::INFINITY, c: -f64::INFINITY);
+    assert_eq!(res.to_string(), "NAN INFINITY -INFINITY");
+
+    let owned = String::from("owned");
+    let res = code!("$a $b $c $d",
+        a: &owned,
+        b: Cow::Borrowed("cow"),
+        c: Rc::<str>::from("rc"),
+        d: Box::<str>::from("box"),
+    );
+    assert_eq!(res.to_string(), "owned cow rc box");
+}
+
+#[test]
+fn string_args() {
+    // Owned and borrowed strings are treated identically.
+    let cases = ["x", "  x", "x  ", " \t", "", "\n  a\n    b\n", "a  \n  b"];
+    for &case in &cases {
+        let borrowed = code!("[$x]", x: case);
+        let owned = code!("[$x]", x: case.to_string());
+        let trimmed = code!("[$x]", x: TrimIndent(case.to_string()));
+        assert_eq!(borrowed.to_string(), owned.to_string(), "{:?}", case);
+        assert_eq!(borrowed.to_string(), trimmed.to_string(), "{:?}", case);
+    }
+    assert_eq!(code!("[$x]", x: String::from("  x  ")).to_string(), "[x]");
+
+    let res = code!("[$x]", x: KeepIndent("  a  \n\n b"));
+    assert_eq!(res.to_string(), "[  a\n\n  b]");
+
+    let arena = CodeArena::new();
+    let res = code_in!(&arena, "[$x]", x: KeepIndent(" a\n  b"));
+    assert_eq!(res.to_string(), "[ a\n   b]");
+}
@@ -1,9 +1,7 @@
-use SourceLoc;
+use render::Highlight;
 
 use ansi_term::{Style, Colour};
 
-use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
-
 /// This is a random sequence which was generated. It is used to determine which
 /// order to display colours in when drawing the colourized output.
 ///
@@ -46,16 +44,42 @@ fn map_to_colour(i: usize) -> Style {
     style
 }
 
-static CURRENT_COLOUR: AtomicUsize = ATOMIC_USIZE_INIT;
-impl SourceLoc {
-    pub(crate) fn style(&self) -> Style {
-        let idx = self.colour.load(Ordering::SeqCst);
-        if idx == 0 {
-            let idx = CURRENT_COLOUR.fetch_add(1, Ordering::SeqCst);
-            self.colour.compare_and_swap(0, idx + 1, Ordering::SeqCst);
-            return self.style();
-        }
-        map_to_colour(idx - 1)
+/// The colours which are available in terminals supporting only the basic 16
+/// ANSI colours, excluding black and white.
+const ANSI16_SEQUENCE: &[Colour] = &[
+    Colour::Red, Colour::Green, Colour::Yellow,
+    Colour::Blue, Colour::Purple, Colour::Cyan,
+];
+
+fn map_to_colour16(i: usize) -> Style {
+    let colour = ANSI16_SEQUENCE[i % ANSI16_SEQUENCE.len()];
+
+    // Alternate between coloured backgrounds and coloured text, to double the
+    // number of distinct styles.
+    if (i / ANSI16_SEQUENCE.len()).is_multiple_of(2) {
+        Style::new().on(colour).fg(Colour::Black)
+    } else {
+        Style::new().fg(colour)
+    }
+}
+
+/// Get the style to use for the source location with index `loc` in the given
+/// highlighting mode. Substitutions without their own source location are
+/// additionally bold and underlined.
+pub(crate) fn style(
+    highlight: Highlight,
+    loc: Option<usize>,
+    subst: bool,
+) -> Style {
+    let style = match (loc, highlight) {
+        (None, _) => Style::default(),
+        (Some(i), Highlight::Ansi16) => map_to_colour16(i),
+        (Some(i), _) => map_to_colour(i),
+    };
+    if subst {
+        style.bold().underline()
+    } else {
+        style
     }
 }
 
@@ -64,6 +88,6 @@ fn colour_test() {
     // This test doesn't actually assert anything, but rather just is used to
     // visualize all colours by turning off output capturing.
     for i in 0..COLOUR_SEQUENCE.len() {
-        println!("{}", map_to_colour(i).paint("Hello, World!"));
+        ::std::println!("{}", map_to_colour(i).paint("Hello, World!"));
     }
 }
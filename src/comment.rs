@@ -0,0 +1,237 @@
+//! A builder for rendering free-form text as a comment block.
+
+use {BlankLines, Code, CodeArg, Op};
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+#[cfg(test)]
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+/// The syntax used to render a [`Comment`].
+///
+/// [`Comment`]: struct.Comment.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentStyle {
+    /// Each line is prefixed with `// `.
+    Slashes,
+    /// Each line is prefixed with `/// `.
+    TripleSlash,
+    /// A `/*` ... `*/` block, with each line prefixed with ` * `.
+    Block,
+    /// A `/**` ... `*/` block, with each line prefixed with ` * `.
+    DocBlock,
+    /// Each line is prefixed with `# `.
+    Hash,
+    /// A Python `"""` docstring.
+    DocString,
+}
+
+impl CommentStyle {
+    /// Returns the (opening line, line prefix, closing line) for this style.
+    fn parts(
+        self,
+    ) -> (Option<&'static str>, &'static str, Option<&'static str>) {
+        match self {
+            CommentStyle::Slashes => (None, "//", None),
+            CommentStyle::TripleSlash => (None, "///", None),
+            CommentStyle::Block => (Some("/*"), " *", Some(" */")),
+            CommentStyle::DocBlock => (Some("/**"), " *", Some(" */")),
+            CommentStyle::Hash => (None, "#", None),
+            CommentStyle::DocString => (Some("\"\"\""), "", Some("\"\"\"")),
+        }
+    }
+
+    /// Escape any sequences in `line` which would terminate the comment early.
+    fn escape(self, line: &str) -> String {
+        match self {
+            CommentStyle::Block | CommentStyle::DocBlock => {
+                line.replace("*/", "*\\/")
+            }
+            CommentStyle::DocString => {
+                line.replace('\\', "\\\\").replace("\"\"\"", "\\\"\\\"\\\"")
+            }
+            _ => line.to_owned(),
+        }
+    }
+}
+
+/// A [`CodeArg`] which renders multi-line text as a comment block.
+///
+/// Lines of the text are kept as written, aside from trailing whitespace, and
+/// leading and trailing blank lines are removed. If a width is set, each
+/// paragraph is instead re-wrapped so that lines, including the comment
+/// prefix, fit within that many columns where possible.
+///
+/// [`CodeArg`]: trait.CodeArg.html
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::{Comment, CommentStyle};
+/// # fn main() {
+/// let doc = "The number of widgets. */ is escaped.\n\nNever negative.";
+/// let res = code!("
+///     struct S {
+///         $doc
+///         int count;
+///     };",
+///     doc: Comment::new(doc).style(CommentStyle::DocBlock).width(26),
+/// );
+/// assert_eq!(res.to_string(), "\
+/// struct S {
+///     /**
+///      * The number of widgets.
+///      * *\\/ is escaped.
+///      *
+///      * Never negative.
+///      */
+///     int count;
+/// };");
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Comment<S> {
+    text: S,
+    style: CommentStyle,
+    width: Option<usize>,
+}
+
+impl<S: AsRef<str>> Comment<S> {
+    /// Create a new `//`-style comment containing the given text.
+    pub fn new(text: S) -> Self {
+        Comment {
+            text,
+            style: CommentStyle::Slashes,
+            width: None,
+        }
+    }
+
+    /// Set the syntax used to render the comment.
+    pub fn style(mut self, style: CommentStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Re-wrap each paragraph of the text to fit within `width` columns.
+    pub fn width(mut self, width: usize) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    /// Compute the lines of text in the comment body, before escaping.
+    fn lines(&self, prefix: &str) -> Vec<String> {
+        let text = self.text.as_ref();
+        let mut lines: Vec<String> = match self.width {
+            None => text.lines().map(|l| l.trim_end().to_owned()).collect(),
+            Some(width) => {
+                // Leave space for the prefix, and the space following it.
+                let width = width.saturating_sub(prefix.len() + 1);
+                let mut lines = vec![];
+                let mut curr = String::new();
+                // The width of `curr` in characters, rather than bytes.
+                let mut curr_width = 0;
+                for line in text.lines() {
+                    if line.trim().is_empty() {
+                        if !curr.is_empty() {
+                            lines.push(curr.split_off(0));
+                            curr_width = 0;
+                        }
+                        lines.push(String::new());
+                        continue;
+                    }
+                    for word in line.split_whitespace() {
+                        let word_width = word.chars().count();
+                        if !curr.is_empty() {
+                            if curr_width + 1 + word_width > width {
+                                lines.push(curr.split_off(0));
+                                curr_width = 0;
+                            } else {
+                                curr.push(' ');
+                                curr_width += 1;
+                            }
+                        }
+                        curr.push_str(word);
+                        curr_width += word_width;
+                    }
+                }
+                if !curr.is_empty() {
+                    lines.push(curr);
+                }
+                lines.dedup_by(|a, b| a.is_empty() && b.is_empty());
+                lines
+            }
+        };
+
+        while lines.last().is_some_and(|l| l.is_empty()) {
+            lines.pop();
+        }
+        let leading = lines.iter().take_while(|l| l.is_empty()).count();
+        lines.drain(..leading);
+        lines
+    }
+}
+
+impl<S: AsRef<str>> CodeArg for Comment<S> {
+    fn into_code(self) -> Code {
+        let (open, prefix, close) = self.style.parts();
+        let mut ops = vec![];
+        if let Some(open) = open {
+            ops.push(Op::Lit(open));
+        }
+        for line in self.lines(prefix) {
+            if !ops.is_empty() {
+                ops.push(Op::Nl);
+            }
+            let line = if line.is_empty() {
+                prefix.to_owned()
+            } else if prefix.is_empty() {
+                self.style.escape(&line)
+            } else {
+                format!("{} {}", prefix, self.style.escape(&line))
+            };
+            if !line.is_empty() {
+                ops.push(Op::Blob(line.into_boxed_str()));
+            }
+        }
+        if let Some(close) = close {
+            ops.push(Op::Nl);
+            ops.push(Op::Lit(close));
+        }
+
+        // Blank lines without a prefix would otherwise be collapsed, changing
+        // the text of the comment.
+        let code = Code::from_ops(ops);
+        if prefix.is_empty() {
+            code.blank_lines(BlankLines::new())
+        } else {
+            code
+        }
+    }
+}
+
+#[test]
+fn comment_test() {
+    let text = "\nFirst line\n  indented line  \n\nSecond paragraph\n\n";
+    let c = Comment::new(text).style(CommentStyle::TripleSlash).into_code();
+    assert_eq!(
+        c.to_string(),
+        "/// First line\n///   indented line\n///\n/// Second paragraph"
+    );
+
+    let c = Comment::new("a b c d e f").style(CommentStyle::Hash).width(5);
+    assert_eq!(c.into_code().to_string(), "# a b\n# c d\n# e f");
+
+    let c = Comment::new("Says \"\"\"hi\"\"\"").style(CommentStyle::DocString);
+    assert_eq!(
+        c.into_code().to_string(),
+        "\"\"\"\nSays \\\"\\\"\\\"hi\\\"\\\"\\\"\n\"\"\""
+    );
+    let c = Comment::new("a\n\n\n\nb").style(CommentStyle::DocString);
+    assert_eq!(c.into_code().to_string(), "\"\"\"\na\n\n\n\nb\n\"\"\"");
+
+    // Widths are measured in characters, not bytes.
+    let c = Comment::new("été éte ça va").width(10);
+    assert_eq!(c.into_code().to_string(), "// été éte\n// ça va");
+}
@@ -1,27 +1,117 @@
 //! This module provides the implementation of Display for Code.
 
 use super::*;
+use arena::ArenaData;
+use blank::BlankLines;
+use lines::Line;
+use once::OnceDef;
+use render::Highlight;
 
+use alloc::string::String;
+use core::mem;
+#[cfg(test)]
+use alloc::borrow::ToOwned;
+#[cfg(test)]
+use alloc::string::ToString;
+
+#[cfg(feature = "highlight")]
 use ansi_term::Style;
-use std::collections::HashSet;
 
 /// A limiter on the maximum number of consecutive newlines. This reduces the
 /// number of unnecessary newlines which are generated in the target file,
 /// making the output nicer to read.
 const MAX_CONSECUTIVE_NEWLINES: usize = 2;
 
-struct State {
+/// How a chunk of text should be highlighted.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+struct Mark {
+    /// The index of the source location the text came from in the list of
+    /// seen source locations.
+    loc: Option<usize>,
+    /// Whether the text is a substitution without its own source location.
+    subst: bool,
+}
+
+/// Marks applied to the current line, along with the source locations which
+/// have been seen, in the order they were first seen. Each location is coloured
+/// by its index in this list, so colours only depend on the code being
+/// rendered.
+type Styles = (Vec<(usize, Mark)>, Vec<&'static SourceLoc>);
+
+/// Writes the chunks of a highlighted line.
+struct Painter<'a> {
+    highlight: Highlight,
+    seen: &'a [&'static SourceLoc],
+    /// The source location of the currently open marker, in `Markers` mode.
+    open: Option<usize>,
+}
+
+impl<'a> Painter<'a> {
+    fn write(
+        &mut self,
+        f: &mut dyn fmt::Write,
+        mark: Mark,
+        text: &str,
+    ) -> fmt::Result {
+        if text.is_empty() {
+            return Ok(());
+        }
+        if self.highlight != Highlight::Markers {
+            return paint(f, self.highlight, Some(mark), text);
+        }
+
+        // Substitutions are shown as part of their surrounding code, so only
+        // a change of source location requires a new marker.
+        if mark.loc != self.open {
+            self.finish(f)?;
+            if let Some(idx) = mark.loc {
+                let loc = self.seen[idx];
+                write!(f, "«{}:{}|", loc.file, loc.line)?;
+            }
+            self.open = mark.loc;
+        }
+        f.write_str(text)
+    }
+
+    fn finish(&mut self, f: &mut dyn fmt::Write) -> fmt::Result {
+        if self.open.take().is_some() {
+            f.write_str("»")?;
+        }
+        Ok(())
+    }
+}
+
+struct State<'a> {
     curr: String,
     nls: usize,
     // NOTE: Default value is good for max_nls, as we don't want to generate any
     // leading newlines in the final output.
     max_nls: usize,
     offset: usize,
-    styles: Option<(Vec<(usize, Style)>, HashSet<&'static SourceLoc>)>,
+    styles: Option<Styles>,
+    highlight: Highlight,
+    arena: Option<&'a ArenaData>,
+    /// Definitions referenced by `EmitOnce` which have not been emitted yet.
+    once: Vec<&'a OnceDef>,
+    /// If set, lines are collected here rather than being written out.
+    lines: Option<Vec<Line>>,
+    /// Whether the current line contains raw text, so must be written even if
+    /// it is blank.
+    keep_line: bool,
+    /// Whether the pending newlines came from raw text, so must be written
+    /// exactly.
+    exact_nls: bool,
+    /// The policy for blank lines in the current fragment, if it has one.
+    blank: Option<BlankLines>,
+    /// The policy in effect when the pending newlines were seen.
+    nls_blank: Option<BlankLines>,
+    /// The policy for the current fragment, which takes effect once its first
+    /// line is written, so newlines leading up to it are treated as before.
+    next_blank: Option<BlankLines>,
 }
 
-impl State {
-    fn new(debug_highlight: bool) -> Self {
+impl<'a> State<'a> {
+    fn new(arena: Option<&'a ArenaData>, highlight: Highlight) -> Self {
         State {
             curr: String::new(),
             nls: 0,
@@ -30,18 +120,49 @@ impl State {
             max_nls: 0,
             offset: 0,
 
-            styles: if debug_highlight {
+            styles: if highlight != Highlight::None {
                 // Start with the default style.
-                Some((vec![(0, Style::default())], HashSet::new()))
+                Some((vec![(0, Mark::default())], Vec::new()))
             } else {
                 None
             },
+            highlight,
+            arena,
+            once: Vec::new(),
+            lines: None,
+            keep_line: false,
+            exact_nls: false,
+            blank: None,
+            nls_blank: None,
+            next_blank: None,
         }
     }
 
+    /// Render `ops`, indented by `indent` spaces.
+    fn render(
+        &mut self,
+        f: &mut dyn fmt::Write,
+        ops: &'a [Op],
+        indent: usize,
+    ) -> fmt::Result {
+        for _ in 0..indent { self.curr.push(' '); }
+
+        // NOTE: `InnerRef` ops repeat a fragment which has already been
+        // searched, so they don't need to be followed.
+        let mut once = OnceDefs { defs: Vec::new(), anchor: false };
+        once.collect(ops, self.arena);
+        self.once = once.defs;
+        if !once.anchor {
+            self.emit_once(f, indent)?;
+        }
+
+        self.run(f, ops, indent)?;
+        self.flush(f, 0)
+    }
+
     fn run(
         &mut self,
-        f: &mut fmt::Formatter,
+        f: &mut dyn fmt::Write,
         ops: &[Op],
         base_offset: usize,
     ) -> fmt::Result {
@@ -50,101 +171,264 @@ impl State {
 
             // If no styles are applied, it's a basic substitution. Make the text
             // bold and underlined.
-            styles.push((self.curr.len(), restore_style.bold().underline()));
+            let subst = Mark { subst: true, ..restore_style };
+            styles.push((self.curr.len(), subst));
             Some(restore_style)
         } else {
             None
         };
 
-        for (idx, op) in ops.iter().enumerate() {
-            match *op {
-                Op::Nl => {
-                    self.flush(f, base_offset)?;
-
-                    // Record that we have seen an additional newline, and clamp
-                    // the maximum number of consecutive newlines to
-                    // MAX_CONSECUTIVE_NEWLINES.
-                    if self.nls < self.max_nls {
-                        self.nls += 1;
-                    }
-                }
+        for idx in 0..ops.len() {
+            self.op(f, ops, idx, base_offset)?;
+        }
 
-                Op::Lit(ref seg) => {
-                    self.offset += seg.len();
-                    self.curr.push_str(seg);
-                }
-                Op::Blob(ref seg) => {
-                    self.offset += seg.len();
-                    self.curr.push_str(seg);
-                }
+        if let Some((ref mut styles, _)) = self.styles {
+           styles.push((self.curr.len(), restore_style.unwrap()));
+        }
+        Ok(())
+    }
 
-                Op::Inner(ref inner) => {
-                    let offset = self.offset;
-                    self.run(f, inner, offset)?;
-                }
+    /// Run the op at `ops[idx]`.
+    fn op(
+        &mut self,
+        f: &mut dyn fmt::Write,
+        ops: &[Op],
+        idx: usize,
+        base_offset: usize,
+    ) -> fmt::Result {
+        match ops[idx] {
+            Op::Nl => self.newline(f, base_offset)?,
 
-                Op::InnerRef(back) => {
-                    let offset = self.offset;
-                    assert!(back <= idx, "Invalid index");
-                    match ops[idx - back] {
-                        Op::Inner(ref inner) => {
-                            self.run(f, inner, offset)?;
-                        }
-                        _ => panic!("Invalid type at index"),
+            Op::Lit(seg) => {
+                self.offset += seg.len();
+                self.curr.push_str(seg);
+            }
+            Op::Blob(ref seg) => {
+                self.offset += seg.len();
+                self.curr.push_str(seg);
+            }
+
+            Op::RawLit(text) => self.raw(f, text)?,
+            Op::RawBlob(ref text) => self.raw(f, text)?,
+
+            Op::Inner(ref inner) => {
+                let offset = self.offset;
+                self.run(f, inner, offset)?;
+            }
+            Op::Indented(n, ref inner) => {
+                for _ in 0..n { self.curr.push(' '); }
+                self.offset += n;
+                let offset = self.offset;
+                self.run(f, inner, offset)?;
+            }
+            Op::BlankLines(policy, ref inner) => {
+                let outer = (self.blank, self.next_blank.replace(policy));
+                let offset = self.offset;
+                self.run(f, inner, offset)?;
+                self.blank = outer.0;
+                self.next_blank = outer.1;
+            }
+            Op::Dedented(ref inner) => {
+                // If the fragment starts a line, remove the indentation which
+                // has already been written for it.
+                if self.curr.chars().all(char::is_whitespace) {
+                    self.curr.clear();
+                    self.offset = 0;
+                    if let Some((ref mut styles, _)) = self.styles {
+                        for style in styles.iter_mut() { style.0 = 0; }
                     }
                 }
+                self.run(f, inner, 0)?;
+            }
 
-                Op::SourceLoc(sourceloc) => {
-                    if let Some((ref mut styles, ref mut seen)) = self.styles {
-                        styles.push((self.curr.len(), sourceloc.style()));
-                        seen.insert(sourceloc);
+            Op::InnerRef(back) => {
+                assert!(back <= idx, "Invalid index");
+                // NOTE: `ArenaText` becomes a `Blob` when it is copied out of
+                // its arena.
+                match ops[idx - back] {
+                    Op::Inner(..) | Op::ArenaInner(..) |
+                    Op::ArenaText(..) | Op::Blob(..) => {
+                        self.op(f, ops, idx - back, base_offset)?;
                     }
+                    _ => panic!("Invalid type at index"),
+                }
+            }
+
+            Op::SourceLoc(sourceloc) => {
+                if let Some((ref mut styles, ref mut seen)) = self.styles {
+                    let idx = match seen.iter().position(|&s| s == sourceloc) {
+                        Some(idx) => idx,
+                        None => {
+                            seen.push(sourceloc);
+                            seen.len() - 1
+                        }
+                    };
+                    let mark = Mark { loc: Some(idx), subst: false };
+                    styles.push((self.curr.len(), mark));
                 }
             }
+
+            Op::ArenaText(start, end) => {
+                let arena = self.arena.expect("arena op outside of arena");
+                let seg = &arena.text[start..end];
+                self.offset += seg.len();
+                self.curr.push_str(seg);
+            }
+            Op::ArenaInner(start, end) => {
+                let arena = self.arena.expect("arena op outside of arena");
+                let offset = self.offset;
+                self.run(f, &arena.ops[start..end], offset)?;
+            }
+
+            Op::EmitOnce(ref def) => {
+                self.offset += def.key.len();
+                self.curr.push_str(&def.key);
+            }
+            Op::EmitAnchor => {
+                let offset = self.offset;
+                self.emit_once(f, offset)?;
+            }
         }
+        Ok(())
+    }
 
-        if let Some((ref mut styles, _)) = self.styles {
-           styles.push((self.curr.len(), restore_style.unwrap()));
+    fn newline(
+        &mut self,
+        f: &mut dyn fmt::Write,
+        base_offset: usize,
+    ) -> fmt::Result {
+        self.flush(f, base_offset)?;
+
+        // Record that we have seen an additional newline, and clamp the
+        // maximum number of consecutive newlines to MAX_CONSECUTIVE_NEWLINES,
+        // unless the fragment has its own policy. Leading newlines are never
+        // generated.
+        let unclamped = self.blank.is_some() && self.max_nls != 0;
+        if unclamped || self.nls < self.max_nls {
+            self.nls += 1;
         }
+        self.nls_blank = self.blank;
         Ok(())
     }
 
-    fn flush(
+    /// Emit raw text. Each line is written exactly as given, without
+    /// indentation, and blank lines are not collapsed.
+    fn raw(&mut self, f: &mut dyn fmt::Write, text: &str) -> fmt::Result {
+        for (idx, seg) in text.split('\n').enumerate() {
+            if idx != 0 {
+                self.flush(f, 0)?;
+                self.nls += 1;
+                self.exact_nls = true;
+            }
+            if !seg.is_empty() {
+                self.offset += seg.len();
+                self.curr.push_str(seg);
+                self.keep_line = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit the pending definitions referenced by `EmitOnce`, each followed by
+    /// a blank line.
+    fn emit_once(
         &mut self,
-        f: &mut fmt::Formatter,
+        f: &mut dyn fmt::Write,
         base_offset: usize,
     ) -> fmt::Result {
-        use std::fmt::Write;
+        for def in mem::take(&mut self.once) {
+            self.run(f, &def.code.ops, base_offset)?;
+            self.newline(f, base_offset)?;
+            self.newline(f, base_offset)?;
+        }
+        Ok(())
+    }
+
+    /// Write out the current line, preceded by any pending newlines.
+    fn write_line(&mut self, f: &mut dyn fmt::Write) -> fmt::Result {
+        for _ in 0..self.nls { f.write_char('\n')?; }
+
+        if let Some((ref styles, ref seen)) = self.styles {
+            // We're styling, make sure to write out the correct styles!
+            let mut painter = Painter {
+                highlight: self.highlight,
+                seen,
+                open: None,
+            };
+            let mut c = 0;
+            let mut mark = Mark::default();
+            for &(idx, new_mark) in styles {
+                painter.write(f, mark, &self.curr[c..idx])?;
+                c = idx;
+                mark = new_mark;
+            }
+            painter.write(f, mark, &self.curr[c..])?;
+            painter.finish(f)
+        } else {
+            // Not styling - we don't have to write out styles.
+            f.write_str(&self.curr)
+        }
+    }
 
+    /// Record the current line, preceded by a blank line for each pending
+    /// newline after the first.
+    fn collect_line(&mut self) {
+        // NOTE: Only spaces count as indentation. Any other leading
+        // whitespace, such as a tab in raw text, is kept in the line's text.
+        let text = self.curr.trim_start_matches(' ');
+        let line = Line {
+            indent: self.curr.len() - text.len(),
+            text: text.into(),
+            source_loc: self.line_origin(),
+        };
+        let lines = self.lines.as_mut().unwrap();
+        for _ in 1..self.nls { lines.push(Line::blank()); }
+        lines.push(line);
+    }
+
+    /// Find the source location which produced the first non-whitespace
+    /// character of the current line.
+    fn line_origin(&self) -> Option<&'static SourceLoc> {
+        let (ref styles, ref seen) = *self.styles.as_ref()?;
+        let start = self.curr.len() - self.curr.trim_start().len();
+        let &(_, mark) = styles.iter()
+            .take_while(|&&(idx, _)| idx <= start)
+            .last()?;
+        mark.loc.map(|idx| seen[idx])
+    }
+
+    fn flush(
+        &mut self,
+        f: &mut dyn fmt::Write,
+        base_offset: usize,
+    ) -> fmt::Result {
         // If we have a non-blank line, flush it.
-        if !self.curr.chars().all(char::is_whitespace) {
+        if self.keep_line || !self.curr.chars().all(char::is_whitespace) {
             // XXX(hacky?): Don't generate more than 1 newline before a line
             // starting with a closing brace.
-            if self.curr.trim_left().starts_with(&['}', ')', ']'][..]) {
+            let trimmed = self.curr.trim_start();
+            if self.exact_nls {
+                // Newlines from raw text are written as they are.
+            } else if let Some(policy) = self.nls_blank {
+                self.nls = policy.clamp(self.nls);
+            } else if trimmed.starts_with(&['}', ')', ']'][..]) {
                 self.nls = usize::min(self.nls, 1);
             }
 
-            for _ in 0..self.nls { f.write_char('\n')?; }
-            self.nls = 0;
-
-            if let Some((ref styles, _)) = self.styles {
-                // We're styling, make sure to write out the correct styles!
-                let mut c = 0;
-                let mut style = Style::default();
-                for &(idx, new_style) in styles {
-                    write!(f, "{}", style.paint(&self.curr[c..idx]))?;
-                    c = idx;
-                    style = new_style;
-                }
-                write!(f, "{}", style.paint(&self.curr[c..]))?;
+            if self.lines.is_some() {
+                self.collect_line();
             } else {
-                // Not styling - we don't have to write out styles.
-                f.write_str(&self.curr)?;
+                self.write_line(f)?;
+            }
+            self.nls = 0;
+            self.exact_nls = false;
+            if let Some(policy) = self.next_blank.take() {
+                self.blank = Some(policy);
             }
 
             // XXX(hacky?): Don't generate more than 1 newline after a line
             // starting with a curly brace.
-            if self.curr.trim_right().ends_with(&['{', '(', '['][..]) {
+            if self.curr.trim_end().ends_with(&['{', '(', '['][..]) {
                 self.max_nls = 1;
             } else {
                 self.max_nls = MAX_CONSECUTIVE_NEWLINES;
@@ -153,6 +437,7 @@ impl State {
 
         // Reset our offset.
         self.offset = base_offset;
+        self.keep_line = false;
 
         // Reset curr to the base offset
         self.curr.clear();
@@ -172,23 +457,182 @@ impl State {
     }
 }
 
-pub(crate) fn do_display(
-    code: &Code,
+/// Write `text` in the colour for `mark` in a colour highlighting mode, or in
+/// bold if `mark` is `None`.
+#[cfg(feature = "highlight")]
+fn paint(
+    f: &mut dyn fmt::Write,
+    highlight: Highlight,
+    mark: Option<Mark>,
+    text: &str,
+) -> fmt::Result {
+    let style = match mark {
+        Some(mark) => colours::style(highlight, mark.loc, mark.subst),
+        None => Style::new().bold(),
+    };
+    write!(f, "{}", style.paint(text))
+}
+
+#[cfg(not(feature = "highlight"))]
+fn paint(
+    _: &mut dyn fmt::Write,
+    _: Highlight,
+    _: Option<Mark>,
+    _: &str,
+) -> fmt::Result {
+    unreachable!("colour modes require the `highlight` feature")
+}
+
+/// Write a line of the legend, coloured to match the source location `loc`.
+fn write_legend(
+    f: &mut dyn fmt::Write,
+    highlight: Highlight,
+    loc: Option<usize>,
+    text: &str,
+) -> fmt::Result {
+    if highlight == Highlight::Markers {
+        return f.write_str(text);
+    }
+    let mark = loc.map(|loc| Mark { loc: Some(loc), subst: false });
+    paint(f, highlight, mark, text)
+}
+
+/// The definitions referenced by `EmitOnce` within some code, in the order in
+/// which they should be emitted.
+struct OnceDefs<'a> {
+    defs: Vec<&'a OnceDef>,
+    anchor: bool,
+}
+
+impl<'a> OnceDefs<'a> {
+    fn collect(&mut self, ops: &'a [Op], arena: Option<&'a ArenaData>) {
+        for op in ops {
+            match *op {
+                Op::Inner(ref inner) |
+                Op::Indented(_, ref inner) |
+                Op::Dedented(ref inner) |
+                Op::BlankLines(_, ref inner) => self.collect(inner, arena),
+                Op::ArenaInner(start, end) => {
+                    let data = arena.expect("arena op outside of arena");
+                    self.collect(&data.ops[start..end], arena);
+                }
+                Op::EmitOnce(ref def) => {
+                    if self.defs.iter().any(|d| d.key == def.key) {
+                        continue;
+                    }
+
+                    // Definitions which this one depends on are emitted before
+                    // it. It is added to the list afterwards, so mark it as
+                    // seen with a placeholder to handle cycles.
+                    let idx = self.defs.len();
+                    self.defs.push(def);
+                    self.collect(&def.code.ops, arena);
+                    self.defs.remove(idx);
+                    self.defs.push(def);
+                }
+                Op::EmitAnchor => self.anchor = true,
+                _ => {}
+            }
+        }
+    }
+}
+
+pub(crate) fn do_display<'a>(
+    ops: &'a [Op],
+    arena: Option<&'a ArenaData>,
     f: &mut fmt::Formatter,
     indent: usize,
-    debug_highlight: bool,
+    highlight: Highlight,
 ) -> fmt::Result {
-    let mut state = State::new(debug_highlight);
-    for _ in 0..indent { state.curr.push(' '); }
-    state.run(f, &code.ops, indent)?;
-    state.flush(f, 0)?;
+    let highlight = highlight.resolve();
+    let mut state = State::new(arena, highlight);
+    state.render(f, ops, indent)?;
 
     if let Some((_, ref seen)) = state.styles {
-        write!(f, "{}", Style::new().bold().paint("\n  LEGEND"))?;
-        for seen in seen {
-            let entry = seen.style().paint(format!("{}:{}:{}", seen.file, seen.line, seen.column));
-            write!(f, "\n    {}", entry)?;
+        // Sort the legend by location, rather than by colour.
+        let mut legend: Vec<_> = seen.iter().enumerate().collect();
+        legend.sort_by_key(|&(_, loc)| (loc.file, loc.line, loc.column));
+
+        write_legend(f, highlight, None, "\n  LEGEND")?;
+        for (idx, loc) in legend {
+            f.write_str("\n    ")?;
+            let entry = format!("{}:{}:{}", loc.file, loc.line, loc.column);
+            write_legend(f, highlight, Some(idx), &entry)?;
         }
     }
     Ok(())
 }
+
+/// Render `ops` as a list of lines, recording where each line came from.
+pub(crate) fn collect_lines(ops: &[Op]) -> Vec<Line> {
+    // NOTE: Source locations are tracked by the highlighting machinery, but
+    // nothing is written, so the highlighting mode doesn't matter.
+    let mut state = State::new(None, Highlight::Markers);
+    state.lines = Some(Vec::new());
+    state.render(&mut String::new(), ops, 0)
+        .expect("collecting lines cannot fail");
+    state.lines.unwrap()
+}
+
+/// Render `ops` as text, without emitting the definitions referenced by
+/// `EmitOnce`.
+pub(crate) fn render_text(ops: &[Op]) -> String {
+    let mut res = String::new();
+    let mut state = State::new(None, Highlight::None);
+    state.run(&mut res, ops, 0)
+        .and_then(|()| state.flush(&mut res, 0))
+        .expect("rendering to a string cannot fail");
+    res
+}
+
+#[cfg(feature = "highlight")]
+#[test]
+fn deterministic_highlight() {
+    let inner = code!("inner");
+    let outer = code!("$inner outer", inner: inner);
+    let opts = RenderOptions::new().highlight(Highlight::Ansi256);
+    let first = outer.render(&opts).to_string();
+
+    // Rendering other code in between doesn't affect the colours chosen.
+    let _ = code!("other").render(&opts).to_string();
+    assert_eq!(outer.render(&opts).to_string(), first);
+
+    // The legend is sorted by location, even though `outer` was seen first.
+    let legend = &first[first.find("LEGEND").unwrap()..];
+    let lines: Vec<u32> = legend.split("display.rs:").skip(1).map(|s| {
+        s[..s.find(':').unwrap()].parse().unwrap()
+    }).collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0] < lines[1]);
+}
+
+#[test]
+fn highlight_modes() {
+    let inner = code!("inner");
+    let outer = code!("
+        $inner $x
+        done",
+        inner: inner,
+        x: "x",
+    );
+    let opts = RenderOptions::new().highlight(Highlight::Markers);
+    let markers = outer.render(&opts);
+    let loc = |l: &str| l.split('|').next().unwrap().to_owned();
+    let rendered = markers.to_string();
+    let lines: Vec<&str> = rendered.lines().collect();
+    let (inner_loc, outer_loc) = (loc(lines[0]), loc(lines[1]));
+    assert_eq!(lines[0], format!("{}|inner»{}| x»", inner_loc, outer_loc));
+    assert_eq!(lines[1], format!("{}|done»", outer_loc));
+    assert_eq!(lines[2], "  LEGEND");
+    assert!(!rendered.contains('\x1b'));
+
+    // Colour modes fall back to markers without the `highlight` feature.
+    let opts = RenderOptions::new().highlight(Highlight::Ansi16);
+    let ansi16 = outer.render(&opts).to_string();
+    if cfg!(feature = "highlight") {
+        assert!(ansi16.contains("\x1b[41;30m"));
+        assert!(!ansi16.contains("5;"));
+    } else {
+        assert_eq!(ansi16, rendered);
+    }
+}
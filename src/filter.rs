@@ -0,0 +1,298 @@
+//! This module provides filters, which transform the text of a substitution
+//! written as `${name|filter}`, and `Filters`, which defines new filters.
+
+use {CamelCase, LowerCamelCase, SnakeCase, ScreamingSnakeCase, KebabCase};
+use {CIdent, CppIdent, RustIdent, PyIdent, JavaIdent};
+use {CStr, RustStr, JsonStr, PyStr, ShellWord};
+use {Code, CodeArg, Comment, CommentStyle};
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A user-defined filter.
+enum Filter {
+    Text(Box<dyn Fn(&str) -> String + Send + Sync>),
+    Code(Box<dyn Fn(&str) -> Code + Send + Sync>),
+}
+
+/// The result of applying a filter.
+pub(crate) enum Filtered {
+    /// Text, which is indented like the text it was produced from.
+    Text(String),
+    /// Code, which is indented like any other substituted fragment.
+    Code(Code),
+}
+
+/// A set of filters which can be applied to substitutions written as
+/// `${name|filter}`, in templates passed to [`code_with!`], or to
+/// [`code_in!`] for a [`CodeArena`] using them.
+///
+/// The built-in filters are always available, and are the only filters
+/// available to [`code!`]. They are:
+///
+/// * `upper`, `lower` and `trim`.
+/// * `camel`, `lower_camel`, `snake`, `screaming_snake` and `kebab`, which
+///   behave like [`CamelCase`] and the other naming convention wrappers.
+/// * `c_ident`, `cpp_ident`, `rust_ident`, `py_ident` and `java_ident`, which
+///   behave like [`CIdent`] and the other identifier wrappers.
+/// * `c_str`, `rust_str`, `json_str`, `py_str` and `shell`, which behave like
+///   [`CStr`] and the other quoting wrappers.
+/// * `comment`, `c_comment`, `doc_comment`, `rust_doc`, `hash_comment` and
+///   `docstring`, which render the text as a [`Comment`] in each
+///   [`CommentStyle`].
+/// * `xml`, which escapes `&`, `<`, `>`, `"` and `'` as XML entities.
+///
+/// If the text produced by a filter added with [`add`] spans several lines,
+/// they are indented like the lines of a substituted `&str`, unless the text
+/// it was applied to contained [`Raw`] text, in which case it is emitted
+/// exactly as written. Filters added with [`add_code`], such as the comment
+/// and quoting filters, produce code which is substituted like any other
+/// argument, so may use `Raw` to control this. When filters are chained, each
+/// is applied to the rendered text of the one before it.
+///
+/// [`code_with!`]: macro.code_with.html
+/// [`code_in!`]: macro.code_in.html
+/// [`code!`]: macro.code.html
+/// [`CodeArena`]: struct.CodeArena.html
+/// [`CamelCase`]: struct.CamelCase.html
+/// [`CIdent`]: struct.CIdent.html
+/// [`CStr`]: struct.CStr.html
+/// [`Comment`]: struct.Comment.html
+/// [`CommentStyle`]: enum.CommentStyle.html
+/// [`add`]: #method.add
+/// [`Raw`]: struct.Raw.html
+/// [`add_code`]: #method.add_code
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::Filters;
+/// # fn main() {
+/// let filters = Filters::new()
+///     .add("reverse", |s| s.chars().rev().collect());
+///
+/// let name = "max_size";
+/// let res = code_with!(&filters,
+///     "#define ${name|upper} ${name|reverse|c_str}",
+///     name,
+/// );
+/// assert_eq!(res.to_string(), "#define MAX_SIZE \"ezis_xam\"");
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Filters {
+    filters: Vec<(String, Filter)>,
+}
+
+impl Filters {
+    /// Create a set containing only the built-in filters.
+    pub fn new() -> Self {
+        Filters { filters: Vec::new() }
+    }
+
+    /// Add a filter with the given name, which produces text. A filter added
+    /// with the same name as an existing filter, including a built-in filter,
+    /// replaces it.
+    pub fn add<K, F>(self, name: K, filter: F) -> Self
+    where
+        K: Into<String>,
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.insert(name.into(), Filter::Text(Box::new(filter)))
+    }
+
+    /// Add a filter with the given name, which produces code.
+    pub fn add_code<K, F>(self, name: K, filter: F) -> Self
+    where
+        K: Into<String>,
+        F: Fn(&str) -> Code + Send + Sync + 'static,
+    {
+        self.insert(name.into(), Filter::Code(Box::new(filter)))
+    }
+
+    fn insert(mut self, name: String, filter: Filter) -> Self {
+        self.filters.retain(|f| f.0 != name);
+        self.filters.push((name, filter));
+        self
+    }
+
+    /// Apply the filter with the given name to `s`, panicking if there isn't
+    /// one.
+    pub(crate) fn apply(&self, name: &str, s: &str) -> Filtered {
+        match self.filters.iter().find(|f| f.0 == name) {
+            Some(&(_, Filter::Text(ref filter))) => Filtered::Text(filter(s)),
+            Some(&(_, Filter::Code(ref filter))) => Filtered::Code(filter(s)),
+            None => builtin(name, s)
+                .unwrap_or_else(|| panic!("Unknown filter {}", name)),
+        }
+    }
+}
+
+impl fmt::Debug for Filters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.filters.iter().map(|f| &f.0)).finish()
+    }
+}
+
+fn xml(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&apos;"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+/// Apply the built-in filter with the given name to `s`, if there is one.
+fn builtin(name: &str, s: &str) -> Option<Filtered> {
+    let style = match name {
+        "comment" => Some(CommentStyle::Slashes),
+        "c_comment" => Some(CommentStyle::Block),
+        "doc_comment" => Some(CommentStyle::DocBlock),
+        "rust_doc" => Some(CommentStyle::TripleSlash),
+        "hash_comment" => Some(CommentStyle::Hash),
+        "docstring" => Some(CommentStyle::DocString),
+        _ => None,
+    };
+    if let Some(style) = style {
+        let comment = Comment::new(s).style(style).into_code();
+        return Some(Filtered::Code(comment));
+    }
+
+    // NOTE: Quoted strings which span several lines are raw text, so must
+    // not be re-indented.
+    let quoted = match name {
+        "c_str" => CStr(s).into_code(),
+        "rust_str" => RustStr(s).into_code(),
+        "json_str" => JsonStr(s).into_code(),
+        "py_str" => PyStr(s).into_code(),
+        "shell" => ShellWord(s).into_code(),
+        _ => return builtin_text(name, s).map(Filtered::Text),
+    };
+    Some(Filtered::Code(quoted))
+}
+
+/// Apply the built-in filter which produces text with the given name to `s`,
+/// if there is one.
+fn builtin_text(name: &str, s: &str) -> Option<String> {
+    let filter: fn(&str) -> String = match name {
+        "upper" => |s| s.to_uppercase(),
+        "lower" => |s| s.to_lowercase(),
+        "trim" => |s| s.trim().into(),
+        "camel" => |s| CamelCase(s).to_string(),
+        "lower_camel" => |s| LowerCamelCase(s).to_string(),
+        "snake" => |s| SnakeCase(s).to_string(),
+        "screaming_snake" => |s| ScreamingSnakeCase(s).to_string(),
+        "kebab" => |s| KebabCase(s).to_string(),
+        "c_ident" => |s| CIdent(s).to_string(),
+        "cpp_ident" => |s| CppIdent(s).to_string(),
+        "rust_ident" => |s| RustIdent(s).to_string(),
+        "py_ident" => |s| PyIdent(s).to_string(),
+        "java_ident" => |s| JavaIdent(s).to_string(),
+        "xml" => xml,
+        _ => return None,
+    };
+    Some(filter(s))
+}
+
+#[test]
+fn filter_test() {
+    let filters = Filters::new()
+        .add("shout", |s| format!("{}!", s.to_uppercase()))
+        .add("replaced", |_| "old".into())
+        .add("replaced", |_| "new".into())
+        .add("upper", |s| s.to_lowercase());
+
+    let name = "fooBar";
+    let doc = "Line one.\nLine two.";
+    let res = code_with!(&filters, "
+        #define ${name|screaming_snake} ${name|shout|c_str}
+        ${doc|c_comment}
+        <a title=\"${2|xml}\">${name|replaced}</a> ${name|upper}",
+        name,
+        doc,
+        "<\"x\" & 'y'>",
+    );
+    assert_eq!(res.to_string(), "\
+        #define FOO_BAR \"FOOBAR!\"\n\
+        /*\n * Line one.\n * Line two.\n */\n\
+        <a title=\"&lt;&quot;x&quot; &amp; &apos;y&apos;&gt;\">new</a> foobar");
+
+    // Filters apply to the rendered text of code, and before format specs.
+    let inner = code!("$a + $b", a: "x", b: 1);
+    let res = code!("{ ${inner|upper} } [${inner|trim:>7}]", inner);
+    assert_eq!(res.to_string(), "{ X + 1 } [  x + 1]");
+
+    // An arena uses its own filters, and `code!` only the built-in ones.
+    let mut arena = ::CodeArena::new();
+    arena.set_filters(filters);
+    let res = code_in!(&arena, "${name|shout} ${name|upper}", name);
+    assert_eq!(res.to_string(), "FOOBAR! foobar");
+    assert_eq!(code!("${name|upper}", name).to_string(), "FOOBAR");
+
+    // Text and code are indented, unless they contain raw text.
+    let filters = Filters::new()
+        .add("lines", |s| s.replace(' ', "\n"))
+        .add_code("block", |s| code!("{\n    $s\n}", s));
+    let msg = "a\n  b";
+    let res = code_with!(&filters, "
+        if true; then
+            echo ${msg|shell}
+            ${doc|hash_comment}
+            ${2|lines}
+            ${2|block}
+            ${2|block|upper}
+        fi",
+        msg,
+        doc,
+        "x y",
+    );
+    assert_eq!(res.to_string(), "\
+        if true; then\n    \
+            echo 'a\n  b'\n    \
+            # Line one.\n    # Line two.\n    \
+            x\n    y\n    \
+            {\n        x y\n    }\n    \
+            {\n        X Y\n    }\n\
+        fi");
+
+    let body = code!("a;\n\n\n\nb;  ");
+    let res = code!("
+        {
+            ${body|upper}
+            ${r|upper}
+            echo ${msg|shell|upper}
+        }",
+        body,
+        r: ::Raw("x\n  y"),
+        msg,
+    );
+    assert_eq!(res.to_string(), "\
+        {\n    \
+            A;\n\n    B;\n    \
+            X\n  Y\n    \
+            echo 'A\n  B'\n\
+        }");
+
+    // Definitions referenced by an argument are not part of its text.
+    let h = ::EmitOnce::new("h", code!("void h() {}"));
+    let call = code!("$h();", h: h.clone());
+    let res = code!("${h|upper} [${h:>3}] ${call|upper}", h, call);
+    assert_eq!(res.to_string(), "H [  h] H();");
+}
+
+#[test]
+#[should_panic(expected = "Unknown filter shout")]
+fn unknown_filter() {
+    let name = "x";
+    code!("${name|shout}", name);
+}
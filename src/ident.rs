@@ -0,0 +1,342 @@
+//! Wrappers which convert identifiers between naming conventions, and which
+//! escape identifiers that collide with a target language's reserved words.
+
+use {Code, CodeArg, Op};
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+macro_rules! ident_wrapper {($bound:path => $($i:ident),*) => {
+    $( impl<S: $bound> CodeArg for $i<S> {
+        fn into_code(self) -> Code {
+            // Identifiers never contain newlines, so can be stored directly.
+            Code::from_ops(vec![ Op::Blob(self.to_string().into_boxed_str()) ])
+        }
+    } )*
+}}
+
+/// Split an identifier into its component words. Words are separated by any
+/// non-alphanumeric character, by a lowercase letter or digit followed by an
+/// uppercase letter, and before the last capital in a run of capitals which is
+/// followed by a lowercase letter (so `HTTPServer` is `HTTP`, `Server`).
+fn words(s: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = None;
+    let mut chars = s.char_indices().peekable();
+    let mut prev: Option<char> = None;
+    while let Some((idx, c)) = chars.next() {
+        if !c.is_alphanumeric() {
+            if let Some(start) = start.take() {
+                words.push(&s[start..idx]);
+            }
+            prev = None;
+            continue;
+        }
+
+        let next = chars.peek().map(|&(_, c)| c);
+        let boundary = match prev {
+            Some(p) if c.is_uppercase() => {
+                !p.is_uppercase() ||
+                    next.map(char::is_lowercase).unwrap_or(false)
+            }
+            _ => false,
+        };
+        match start {
+            Some(s_idx) if boundary => {
+                words.push(&s[s_idx..idx]);
+                start = Some(idx);
+            }
+            None => start = Some(idx),
+            _ => {}
+        }
+        prev = Some(c);
+    }
+    if let Some(start) = start {
+        words.push(&s[start..]);
+    }
+    words
+}
+
+fn write_joined<F>(
+    f: &mut fmt::Formatter,
+    s: &str,
+    sep: &str,
+    mut case: F,
+) -> fmt::Result
+where
+    F: FnMut(usize, &str, &mut fmt::Formatter) -> fmt::Result,
+{
+    for (idx, word) in words(s).into_iter().enumerate() {
+        if idx != 0 {
+            f.write_str(sep)?;
+        }
+        case(idx, word, f)?;
+    }
+    Ok(())
+}
+
+fn write_lower(word: &str, f: &mut fmt::Formatter) -> fmt::Result {
+    word.chars().flat_map(char::to_lowercase).try_for_each(|c| f.write_char(c))
+}
+
+fn write_upper(word: &str, f: &mut fmt::Formatter) -> fmt::Result {
+    word.chars().flat_map(char::to_uppercase).try_for_each(|c| f.write_char(c))
+}
+
+fn write_capitalized(word: &str, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut chars = word.chars();
+    if let Some(c) = chars.next() {
+        c.to_uppercase().try_for_each(|c| f.write_char(c))?;
+    }
+    write_lower(chars.as_str(), f)
+}
+
+/// Convert an identifier to `UpperCamelCase`.
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::{CamelCase, ScreamingSnakeCase, KebabCase};
+/// # fn main() {
+/// let res = code!("$a $b $c",
+///     a: CamelCase("http_server"),
+///     b: ScreamingSnakeCase("maxValue"),
+///     c: KebabCase("HTTPServer2"),
+/// );
+/// assert_eq!(res.to_string(), "HttpServer MAX_VALUE http-server2");
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct CamelCase<S>(pub S);
+
+impl<S: AsRef<str>> fmt::Display for CamelCase<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_joined(f, self.0.as_ref(), "", |_, w, f| write_capitalized(w, f))
+    }
+}
+
+/// Convert an identifier to `lowerCamelCase`.
+#[derive(Debug, Clone, Copy)]
+pub struct LowerCamelCase<S>(pub S);
+
+impl<S: AsRef<str>> fmt::Display for LowerCamelCase<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_joined(f, self.0.as_ref(), "", |idx, w, f| {
+            if idx == 0 { write_lower(w, f) } else { write_capitalized(w, f) }
+        })
+    }
+}
+
+/// Convert an identifier to `snake_case`.
+#[derive(Debug, Clone, Copy)]
+pub struct SnakeCase<S>(pub S);
+
+impl<S: AsRef<str>> fmt::Display for SnakeCase<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_joined(f, self.0.as_ref(), "_", |_, w, f| write_lower(w, f))
+    }
+}
+
+/// Convert an identifier to `SCREAMING_SNAKE_CASE`.
+#[derive(Debug, Clone, Copy)]
+pub struct ScreamingSnakeCase<S>(pub S);
+
+impl<S: AsRef<str>> fmt::Display for ScreamingSnakeCase<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_joined(f, self.0.as_ref(), "_", |_, w, f| write_upper(w, f))
+    }
+}
+
+/// Convert an identifier to `kebab-case`.
+#[derive(Debug, Clone, Copy)]
+pub struct KebabCase<S>(pub S);
+
+impl<S: AsRef<str>> fmt::Display for KebabCase<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_joined(f, self.0.as_ref(), "-", |_, w, f| write_lower(w, f))
+    }
+}
+
+ident_wrapper! {
+    AsRef<str> =>
+        CamelCase, LowerCamelCase, SnakeCase, ScreamingSnakeCase, KebabCase
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do",
+    "double", "else", "enum", "extern", "float", "for", "goto", "if", "inline",
+    "int", "long", "register", "restrict", "return", "short", "signed",
+    "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned",
+    "void", "volatile", "while", "_Alignas", "_Alignof", "_Atomic", "_Bool",
+    "_Complex", "_Generic", "_Imaginary", "_Noreturn", "_Static_assert",
+    "_Thread_local",
+    // C23
+    "alignas", "alignof", "bool", "constexpr", "false", "nullptr",
+    "static_assert", "thread_local", "true", "typeof", "typeof_unqual",
+];
+
+const CPP_KEYWORDS: &[&str] = &[
+    "alignas", "alignof", "and", "and_eq", "asm", "auto", "bitand", "bitor",
+    "bool", "break", "case", "catch", "char", "char8_t", "char16_t",
+    "char32_t", "class", "compl", "concept", "const", "consteval", "constexpr",
+    "constinit", "const_cast", "continue", "co_await", "co_return",
+    "co_yield", "decltype", "default", "delete", "do", "double",
+    "dynamic_cast", "else", "enum", "explicit", "export", "extern", "false",
+    "float", "for", "friend", "goto", "if", "inline", "int", "long",
+    "mutable", "namespace", "new", "noexcept", "not", "not_eq", "nullptr",
+    "operator", "or", "or_eq", "private", "protected", "public", "register",
+    "reinterpret_cast", "requires", "return", "short", "signed", "sizeof",
+    "static", "static_assert", "static_cast", "struct", "switch", "template",
+    "this", "thread_local", "throw", "true", "try", "typedef", "typeid",
+    "typename", "union", "unsigned", "using", "virtual", "void", "volatile",
+    "wchar_t", "while", "xor", "xor_eq",
+];
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn",
+    "else", "enum", "extern", "false", "fn", "for", "gen", "if", "impl", "in",
+    "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return",
+    "self", "Self", "static", "struct", "super", "trait", "true", "type",
+    "unsafe", "use", "where", "while",
+    // Reserved for future use
+    "abstract", "become", "box", "do", "final", "macro", "override", "priv",
+    "try", "typeof", "unsized", "virtual", "yield",
+];
+
+/// Rust keywords which cannot be used as raw identifiers.
+const RUST_NON_RAW: &[&str] = &["crate", "self", "Self", "super", "_"];
+
+const PYTHON_KEYWORDS: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break",
+    "class", "continue", "def", "del", "elif", "else", "except", "finally",
+    "for", "from", "global", "if", "import", "in", "is", "lambda", "nonlocal",
+    "not", "or", "pass", "raise", "return", "try", "while", "with", "yield",
+];
+
+const JAVA_KEYWORDS: &[&str] = &[
+    "abstract", "assert", "boolean", "break", "byte", "case", "catch", "char",
+    "class", "const", "continue", "default", "do", "double", "else", "enum",
+    "extends", "final", "finally", "float", "for", "goto", "if", "implements",
+    "import", "instanceof", "int", "interface", "long", "native", "new",
+    "package", "private", "protected", "public", "return", "short", "static",
+    "strictfp", "super", "switch", "synchronized", "this", "throw", "throws",
+    "transient", "try", "void", "volatile", "while", "_",
+    // Literals
+    "true", "false", "null",
+];
+
+/// Write `ident`, appending a trailing `_` if it is in `keywords`.
+fn write_mangled(
+    f: &mut fmt::Formatter,
+    ident: &str,
+    keywords: &[&str],
+) -> fmt::Result {
+    f.write_str(ident)?;
+    if keywords.contains(&ident) {
+        f.write_char('_')?;
+    }
+    Ok(())
+}
+
+/// Escape an identifier which is a C keyword by appending an `_`.
+///
+/// The wrapped value may be any `Display` type, so this composes with the case
+/// conversion wrappers, e.g. `CIdent(SnakeCase(name))`.
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::{CIdent, RustIdent, SnakeCase};
+/// # fn main() {
+/// let res = code!("$a $b $c",
+///     a: CIdent("default"),
+///     b: RustIdent(SnakeCase("Type")),
+///     c: RustIdent("self"),
+/// );
+/// assert_eq!(res.to_string(), "default_ r#type self_");
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct CIdent<S>(pub S);
+
+impl<S: fmt::Display> fmt::Display for CIdent<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_mangled(f, &self.0.to_string(), C_KEYWORDS)
+    }
+}
+
+/// Escape an identifier which is a C++ keyword by appending an `_`.
+#[derive(Debug, Clone, Copy)]
+pub struct CppIdent<S>(pub S);
+
+impl<S: fmt::Display> fmt::Display for CppIdent<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_mangled(f, &self.0.to_string(), CPP_KEYWORDS)
+    }
+}
+
+/// Escape an identifier which is a Rust keyword as a raw identifier (`r#type`).
+///
+/// Keywords which cannot be raw identifiers, such as `self`, have an `_`
+/// appended instead.
+#[derive(Debug, Clone, Copy)]
+pub struct RustIdent<S>(pub S);
+
+impl<S: fmt::Display> fmt::Display for RustIdent<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ident = self.0.to_string();
+        if RUST_NON_RAW.contains(&&ident[..]) {
+            write!(f, "{}_", ident)
+        } else if RUST_KEYWORDS.contains(&&ident[..]) {
+            write!(f, "r#{}", ident)
+        } else {
+            f.write_str(&ident)
+        }
+    }
+}
+
+/// Escape an identifier which is a Python keyword by appending an `_`, as
+/// recommended by PEP 8.
+#[derive(Debug, Clone, Copy)]
+pub struct PyIdent<S>(pub S);
+
+impl<S: fmt::Display> fmt::Display for PyIdent<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_mangled(f, &self.0.to_string(), PYTHON_KEYWORDS)
+    }
+}
+
+/// Escape an identifier which is a Java keyword or literal by appending an `_`.
+#[derive(Debug, Clone, Copy)]
+pub struct JavaIdent<S>(pub S);
+
+impl<S: fmt::Display> fmt::Display for JavaIdent<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_mangled(f, &self.0.to_string(), JAVA_KEYWORDS)
+    }
+}
+
+ident_wrapper! { fmt::Display => CIdent, CppIdent, RustIdent, PyIdent, JavaIdent }
+
+#[test]
+fn ident_test() {
+    assert_eq!(words("fooBar_baz  HTTPServer2x"),
+               ["foo", "Bar", "baz", "HTTP", "Server2x"]);
+    assert_eq!(CamelCase("__foo__bar").to_string(), "FooBar");
+    assert_eq!(LowerCamelCase("XMLHttpRequest").to_string(), "xmlHttpRequest");
+    assert_eq!(SnakeCase("XMLHttpRequest").to_string(), "xml_http_request");
+    assert_eq!(ScreamingSnakeCase("vec3d").to_string(), "VEC3D");
+    assert_eq!(KebabCase("some file.h").to_string(), "some-file-h");
+
+    assert_eq!(CIdent("class").to_string(), "class");
+    assert_eq!(CppIdent("class").to_string(), "class_");
+    assert_eq!(RustIdent("match").to_string(), "r#match");
+    assert_eq!(RustIdent("Self").to_string(), "Self_");
+    assert_eq!(PyIdent("lambda").to_string(), "lambda_");
+    assert_eq!(PyIdent("match").to_string(), "match");
+    assert_eq!(JavaIdent(CamelCase("null")).to_string(), "Null");
+    assert_eq!(JavaIdent(SnakeCase("Null")).to_string(), "null_");
+}
@@ -0,0 +1,94 @@
+//! This module provides `Code::indented` and `Code::dedent`, which control the
+//! indentation of a fragment independently of where it is substituted.
+
+use {Code, Op};
+
+impl Code {
+    /// Indent this code by `n` more columns than it would otherwise be.
+    ///
+    /// Every line of the code is indented, including the first, relative to
+    /// the column at which it is substituted. This makes it possible to build
+    /// nested blocks without a template.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use] extern crate cghelper;
+    /// # use cghelper::Code;
+    /// # fn main() {
+    /// let mut block = Code::new();
+    /// block.push("{\n");
+    /// block.push(code!("a();\nb();").indented(4));
+    /// block.push("\n}");
+    /// assert_eq!(block.to_string(), "{\n    a();\n    b();\n}");
+    /// # }
+    /// ```
+    pub fn indented(&self, n: usize) -> Code {
+        Code::from_ops(vec![ Op::Indented(n, self.ops.clone()) ])
+    }
+
+    /// Don't indent this code, regardless of the column at which it is
+    /// substituted.
+    ///
+    /// If the code is substituted at the start of a line, the indentation
+    /// before it is removed, so its first line also starts at column 0. This
+    /// is useful for code such as preprocessor directives.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use] extern crate cghelper;
+    /// # fn main() {
+    /// let debug = code!("
+    ///     #ifdef DEBUG
+    ///     log(\"here\");
+    ///     #endif").dedent();
+    /// let func = code!("
+    ///     void f() {
+    ///         $debug
+    ///     }",
+    ///     debug: debug,
+    /// );
+    /// assert_eq!(func.to_string(), "\
+    /// void f() {
+    /// #ifdef DEBUG
+    /// log(\"here\");
+    /// #endif
+    /// }");
+    /// # }
+    /// ```
+    pub fn dedent(&self) -> Code {
+        Code::from_ops(vec![ Op::Dedented(self.ops.clone()) ])
+    }
+}
+
+#[test]
+fn indent_test() {
+    use alloc::string::ToString;
+
+    let body = code!("a;\nb;");
+    let res = code!("
+        {
+            $body
+        }
+        x = $inline;",
+        body: body.indented(2),
+        inline: body.indented(1),
+    );
+    assert_eq!(res.to_string(), "{\n      a;\n      b;\n}\nx =  a;\n     b;;");
+
+    // Indentation composes with nested dedented and indented fragments.
+    let nested = code!("
+        #if X
+        $inner
+        #endif",
+        inner: body.indented(4),
+    );
+    let res = code!("
+        {
+            $nested
+        }",
+        nested: nested.dedent(),
+    );
+    assert_eq!(res.to_string(), "{\n#if X\n    a;\n    b;\n#endif\n}");
+}
@@ -1,28 +1,62 @@
-extern crate ansi_term;
-
-// Not a public API
-#[doc(hidden)]
-pub use std::sync::atomic::ATOMIC_USIZE_INIT;
-use std::sync::atomic::AtomicUsize;
-
-use std::fmt;
-use std::iter::FromIterator;
-use std::cmp;
-use std::hash;
-
-mod display;
-mod colours;
-mod codearg;
+#![no_std]
 
-pub use codearg::CodeArg;
+#[cfg(any(feature = "std", test))]
+extern crate std;
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "highlight")]
+extern crate ansi_term;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::iter::FromIterator;
+use core::cmp;
+use core::hash;
+use core::ptr;
 
 /// Mechanism for constructing a [`Code`] object. This macro takes a string
 /// literal as its first argument, with `$substitutions`, and a series of
 /// substitutions as the remaining arguments. Those substitutions can be
 /// anything which implements the [`CodeArg`] trait.
 ///
+/// Each substitution may be written as `name: value`, or as just `name` for a
+/// local variable with the same name. Substitutions can also be referred to
+/// by their position, starting from `$0`, in which case they may be any
+/// expression. Unlike `format!`, variables are not captured implicitly, so
+/// every substitution must be listed.
+///
+/// A substitution may also be written as `${name}`, or with a format spec
+/// using the same syntax as `format!`, such as `${val:#x}`, `${n:04}` or
+/// `${name:<20}`. Format specs may be used with numbers, and with arguments
+/// which are a single line of text. The `x`, `X`, `o` and `b` types may only
+/// be used with integers, and floats are written as literals, such as `1.0`,
+/// unless a precision or the `e` or `E` type is given.
+///
+/// The text of a substitution can be transformed by filters, such as
+/// `${name|upper}` or `${doc|c_comment}`, which are applied in order before
+/// any format spec. See [`Filters`] for the built-in filters, and use
+/// [`code_with!`] to define new ones.
+///
+/// Filters and format specs apply to the text of an argument without the
+/// definitions referenced by any [`EmitOnce`] within it, so an `EmitOnce`
+/// is transformed as its key, and its definition is only emitted if it is
+/// also referenced elsewhere.
+///
+/// Text between `${raw}` and `${endraw}` is emitted exactly as written, with
+/// its indentation and whitespace left unchanged. As these directives are
+/// written like substitutions, `raw` and `endraw` can't be used as the names
+/// of substitutions.
+///
 /// [`Code`]: struct.Code.html
 /// [`CodeArg`]: trait.CodeArg.html
+/// [`EmitOnce`]: struct.EmitOnce.html
+/// [`Filters`]: struct.Filters.html
+/// [`code_with!`]: macro.code_with.html
 ///
 /// # Example Usage
 ///
@@ -50,30 +84,198 @@ pub use codearg::CodeArg;
 ///     printf(\"And my god it is starting to show it!\\n\");
 /// }"
 /// );
+///
+/// let name = "x";
+/// let res = code!("int $name = $1 + $2;", name, 1, name.len());
+/// assert_eq!(res.to_string(), "int x = 1 + 1;");
+///
+/// let res = code!("${name:>4} = ${0:#06x};", 42u16, name);
+/// assert_eq!(res.to_string(), "   x = 0x002a;");
 /// # }
 /// ```
 #[macro_export]
 macro_rules! code {
     ($e:expr) => { code!($e,) };
-    ($e:expr, $($i:ident : $v:expr),* $(,)*) => {
+    ($e:expr, $($args:tt)*) => {
         {
-            static LOC: $crate::SourceLoc = $crate::SourceLoc {
-                line: line!(),
-                column: column!(),
-                file: file!(),
-                colour: $crate::ATOMIC_USIZE_INIT,
-            };
+            static LOC: $crate::SourceLoc =
+                $crate::SourceLoc::new(line!(), column!(), file!());
 
             $crate::Code::build(
                 $e, &LOC,
-                &mut [ $(
-                    $crate::BuildArg::new(stringify!($i), $v)
-                ),* ]
+                &mut $crate::__code_args!(
+                    [$crate::BuildArg::new] [] [] $($args)*,
+                )
+            )
+        }
+    };
+}
+
+/// Like [`code!`], but with the given [`Filters`] available to substitutions
+/// such as `${name|filter}`. The first argument is an expression evaluating to
+/// a `&Filters`, and the remaining arguments are the same as for `code!`.
+///
+/// [`code!`]: macro.code.html
+/// [`Filters`]: struct.Filters.html
+///
+/// # Example Usage
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::Filters;
+/// # fn main() {
+/// let filters = Filters::new().add("header", |s| format!("{}.h", s));
+/// let res = code_with!(&filters, "#include \"${name|header}\"", name: "io");
+/// assert_eq!(res.to_string(), "#include \"io.h\"");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! code_with {
+    ($filters:expr, $e:expr) => { code_with!($filters, $e,) };
+    ($filters:expr, $e:expr, $($args:tt)*) => {
+        {
+            static LOC: $crate::SourceLoc =
+                $crate::SourceLoc::new(line!(), column!(), file!());
+
+            let filters: &$crate::Filters = $filters;
+            $crate::Code::build_with(
+                $e, &LOC, filters,
+                &mut $crate::__code_args!(
+                    [$crate::BuildArg::new] [] [] $($args)*,
+                )
+            )
+        }
+    };
+}
+
+// Not a public API - parses the substitutions passed to `code!`, `code_with!`
+// and `code_in!`, followed by a comma, into an array of `BuildArg`s. Each is
+// created by calling `$new` with the arguments in `$pre`, then the
+// substitution's name and value.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __code_args {
+    ([$($new:tt)*] [$($pre:tt)*] [$($out:tt)*] $(,)*) => { [ $($out)* ] };
+    (
+        [$($new:tt)*] [$($pre:tt)*] [$($out:tt)*]
+        $i:ident : $v:expr, $($rest:tt)*
+    ) => {
+        $crate::__code_args!(
+            [$($new)*] [$($pre)*]
+            [$($out)* $($new)*($($pre)* stringify!($i), $v),]
+            $($rest)*
+        )
+    };
+    (
+        [$($new:tt)*] [$($pre:tt)*] [$($out:tt)*]
+        $i:ident, $($rest:tt)*
+    ) => {
+        $crate::__code_args!(
+            [$($new)*] [$($pre)*]
+            [$($out)* $($new)*($($pre)* stringify!($i), $i),]
+            $($rest)*
+        )
+    };
+    (
+        [$($new:tt)*] [$($pre:tt)*] [$($out:tt)*]
+        $v:expr, $($rest:tt)*
+    ) => {
+        $crate::__code_args!(
+            [$($new)*] [$($pre)*]
+            [$($out)* $($new)*($($pre)* "", $v),]
+            $($rest)*
+        )
+    };
+}
+
+/// Like [`code!`], but builds a [`Fragment`] within the given [`CodeArena`].
+/// The first argument is an expression evaluating to a `&CodeArena`, and the
+/// remaining arguments are the same as for `code!`.
+///
+/// [`code!`]: macro.code.html
+/// [`Fragment`]: struct.Fragment.html
+/// [`CodeArena`]: struct.CodeArena.html
+///
+/// # Example Usage
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::CodeArena;
+/// # fn main() {
+/// let arena = CodeArena::new();
+/// let body = code_in!(&arena, "return $x;", x: 5);
+/// let res = code_in!(&arena, "
+///     int f() {
+///         $body
+///     }",
+///     body: body,
+/// );
+/// assert_eq!(res.to_string(), "int f() {\n    return 5;\n}");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! code_in {
+    ($arena:expr, $e:expr) => { code_in!($arena, $e,) };
+    ($arena:expr, $e:expr, $($args:tt)*) => {
+        {
+            static LOC: $crate::SourceLoc =
+                $crate::SourceLoc::new(line!(), column!(), file!());
+
+            let arena: &$crate::CodeArena = $arena;
+            arena.build(
+                $e, &LOC,
+                &mut $crate::__code_args!(
+                    [$crate::BuildArg::new_in] [arena,] [] $($args)*,
+                )
             )
         }
     };
 }
 
+// NOTE: These modules are declared after `code!` so that they can use it.
+mod display;
+#[cfg(feature = "highlight")]
+mod colours;
+mod codearg;
+mod quote;
+mod ident;
+mod comment;
+mod template;
+mod arena;
+mod render;
+mod visit;
+mod once;
+mod lines;
+mod indent;
+mod blank;
+mod spec;
+mod filter;
+
+pub use codearg::{CodeArg, Joined, KeepIndent, Raw, TrimIndent};
+pub use quote::{CStr, RustStr, JsonStr, PyStr, ShellWord};
+pub use ident::{CamelCase, LowerCamelCase, SnakeCase, ScreamingSnakeCase, KebabCase};
+pub use ident::{CIdent, CppIdent, RustIdent, PyIdent, JavaIdent};
+pub use comment::{Comment, CommentStyle};
+pub use arena::{CodeArena, Fragment};
+pub use render::{Highlight, RenderOptions, Rendered};
+pub use visit::Visitor;
+pub use once::{EmitOnce, EmitAnchor};
+pub use lines::{Line, Lines, SiteStats, Stats};
+pub use blank::BlankLines;
+pub use filter::Filters;
+
+// Not a public API
+#[doc(hidden)]
+pub use template::TemplateCache;
+
+// Not a public API
+#[doc(hidden)]
+pub use arena::ArenaOp;
+
+// Not a public API
+#[doc(hidden)]
+pub use spec::FmtValue;
+
 /// Internal datastructure used to represent how to construct a particular chunk
 /// of Code.
 #[cfg_attr(cghelper_internal_debug, derive(Debug))]
@@ -85,9 +287,21 @@ enum Op {
     Lit(&'static str),
     /// A dynamic blob, containing no newlines - `Box<str>` to keep `Op` small.
     Blob(Box<str>),
-
-    /// An embedded `Code` object - `Box<[Op]>` to keep `Op` small.
-    Inner(Box<[Op]>),
+    /// Text which is emitted exactly as written, and may contain newlines.
+    RawLit(&'static str),
+    /// A dynamic blob of text which is emitted exactly as written.
+    RawBlob(Box<str>),
+
+    /// An embedded `Code` object. The ops are reference counted, so splicing
+    /// the same fragment into many places doesn't copy it.
+    Inner(Arc<Vec<Op>>),
+    /// An embedded `Code` object, indented by the given number of columns
+    /// more than it would otherwise be.
+    Indented(usize, Arc<Vec<Op>>),
+    /// An embedded `Code` object, which is not indented at all.
+    Dedented(Arc<Vec<Op>>),
+    /// An embedded `Code` object, with a policy for its blank lines.
+    BlankLines(blank::BlankLines, Arc<Vec<Op>>),
     /// A reference to another `Code` object which is being repeated.
     ///
     /// Encoded as an offset backward from the index of the current element.
@@ -96,10 +310,28 @@ enum Op {
     /// Information about what source location the next chunk of code comes
     /// from.
     SourceLoc(&'static SourceLoc),
+
+    /// A range of the text buffer in a `CodeArena`, containing no newlines.
+    /// Only valid within the arena's ops.
+    ArenaText(usize, usize),
+    /// A range of the ops in a `CodeArena`. Only valid within the arena's ops.
+    ArenaInner(usize, usize),
+
+    /// A reference to a definition which is emitted once. Rendered as the
+    /// definition's key.
+    EmitOnce(Arc<once::OnceDef>),
+    /// The location at which definitions referenced by `EmitOnce` are emitted.
+    EmitAnchor,
 }
 
 /// This struct represents a chunk of code.
 ///
+/// Cloning a `Code` object, or substituting it into a [`code!`] template, is
+/// cheap, as its contents are shared rather than copied. This makes it
+/// practical to reuse large fragments in many places.
+///
+/// [`code!`]: macro.code.html
+///
 /// Use the `Display` implementation on this type to transform your code into a
 /// string output.
 ///
@@ -107,6 +339,16 @@ enum Op {
 /// format string) on this type will be colorized to help with visualizing the
 /// source of each piece of code.
 ///
+/// `Code` objects compare equal, and hash identically, if they render to the
+/// same string, regardless of how they were built.
+///
+/// `Code` is `Send` and `Sync`, so fragments may be generated on separate
+/// threads and then combined. With the `rayon` feature enabled, a parallel
+/// iterator of [`CodeArg`]s can be collected into `Code`, concatenating the
+/// items in order.
+///
+/// [`CodeArg`]: trait.CodeArg.html
+///
 /// # Example
 ///
 /// ```
@@ -121,20 +363,42 @@ enum Op {
 #[cfg_attr(cghelper_internal_debug, derive(Debug))]
 #[derive(Clone)]
 pub struct Code {
-    ops: Vec<Op>
+    // NOTE: This is an `Arc` rather than an `Rc` so that `Code` remains `Send`.
+    ops: Arc<Vec<Op>>
 }
 
 impl Code {
     /// Create a new `Code` object containing no code.
     pub fn new() -> Self {
-        Code { ops: vec![] }
+        Code::from_ops(vec![])
     }
 
     /// Append the given [`CodeArg`].
     ///
     /// [`CodeArg`]: struct.CodeArg.html
     pub fn push<T: CodeArg>(&mut self, v: T) {
-        self.ops.extend(v.into_code().ops)
+        let code = v.into_code();
+        if self.ops.is_empty() {
+            // Nothing to append to, so share the pushed code's ops directly.
+            *self = code;
+        } else {
+            self.ops_mut().extend(code.into_ops());
+        }
+    }
+
+    fn from_ops(ops: Vec<Op>) -> Self {
+        Code { ops: Arc::new(ops) }
+    }
+
+    /// Get mutable access to the ops, copying them if they are shared.
+    fn ops_mut(&mut self) -> &mut Vec<Op> {
+        Arc::make_mut(&mut self.ops)
+    }
+
+    /// Take the ops, copying them if they are shared. As nested fragments are
+    /// reference counted, this copy is shallow.
+    fn into_ops(self) -> Vec<Op> {
+        Arc::try_unwrap(self.ops).unwrap_or_else(|ops| (*ops).clone())
     }
 
     // Not a public API - use code! instead.
@@ -144,7 +408,28 @@ impl Code {
         sourceloc: &'static SourceLoc,
         args: &mut [BuildArg],
     ) -> Self {
-        str_to_code(tmpl, Some(sourceloc), Some(args), Op::Lit)
+        Code::build_with(tmpl, sourceloc, &Filters::new(), args)
+    }
+
+    // Not a public API - use code_with! instead.
+    #[doc(hidden)]
+    pub fn build_with(
+        tmpl: &'static str,
+        sourceloc: &'static SourceLoc,
+        filters: &Filters,
+        args: &mut [BuildArg],
+    ) -> Self {
+        let mut ops = vec![];
+        sourceloc.template.build(
+            tmpl, sourceloc, args, &mut ops, "", filters,
+        );
+        Code::from_ops(ops)
+    }
+}
+
+impl Default for Code {
+    fn default() -> Self {
+        Code::new()
     }
 }
 
@@ -159,22 +444,48 @@ where
         let mut i = i.into_iter();
         let mut c = i.next()
             .map(|x| x.into_code())
-            .unwrap_or(Code::new());
+            .unwrap_or_default();
         for x in i { c.push(x); }
         c
     }
 }
 
+#[cfg(feature = "rayon")]
+impl<T> rayon::iter::FromParallelIterator<T> for Code
+where
+    T: CodeArg + Send
+{
+    fn from_par_iter<I>(i: I) -> Code
+    where
+        I: rayon::iter::IntoParallelIterator<Item=T>,
+    {
+        use rayon::iter::ParallelIterator;
+
+        // NOTE: `collect` into a `Vec` preserves the order of the items, and
+        // concatenating the resulting `Code` objects is cheap.
+        let codes: Vec<Code> = i.into_par_iter()
+            .map(|x| x.into_code())
+            .collect();
+        codes.into_iter().collect()
+    }
+}
+
+// `Code` is documented to be usable across threads.
+const _: () = {
+    fn check<T: Send + Sync>() {}
+    let _ = check::<Code>;
+};
+
 #[cfg(not(cghelper_internal_debug))]
 impl fmt::Debug for Code {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if f.alternate() {
             f.write_str("Code {\n")?;
-            display::do_display(self, f, 4, true)?;
+            display::do_display(&self.ops, None, f, 4, Highlight::Auto)?;
             f.write_str("\n}")
         } else {
             f.write_str("Code {")?;
-            display::do_display(self, f, 0, false)?;
+            display::do_display(&self.ops, None, f, 0, Highlight::None)?;
             f.write_str("}")
         }
     }
@@ -182,25 +493,126 @@ impl fmt::Debug for Code {
 
 impl fmt::Display for Code {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        display::do_display(self, f, 0, false)
+        display::do_display(&self.ops, None, f, 0, Highlight::None)
     }
 }
 
-// Not a public API
-#[doc(hidden)]
+/// Feeds rendered code into a 64-bit FNV-1a hash.
+struct FnvWriter(u64);
+
+impl fmt::Write for FnvWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x100_0000_01b3);
+        }
+        Ok(())
+    }
+}
+
+/// Checks rendered code against an expected string, failing as soon as it
+/// differs.
+struct MatchWriter<'a> {
+    rest: &'a str,
+}
+
+impl<'a> fmt::Write for MatchWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !self.rest.starts_with(s) {
+            return Err(fmt::Error);
+        }
+        self.rest = &self.rest[s.len()..];
+        Ok(())
+    }
+}
+
+impl Code {
+    /// Compute a hash of the code as rendered by its `Display` implementation,
+    /// without building the rendered string.
+    ///
+    /// The hash is stable across runs and platforms, so it may be used as a key
+    /// in persistent caches.
+    pub fn content_hash(&self) -> u64 {
+        use core::fmt::Write;
+
+        let mut writer = FnvWriter(0xcbf2_9ce4_8422_2325);
+        write!(writer, "{}", self).expect("hashing code cannot fail");
+        writer.0
+    }
+}
+
+// NOTE: Two `Code` objects are equal if they render identically, regardless of
+// how they were built or where they came from.
+impl cmp::PartialEq for Code {
+    fn eq(&self, other: &Self) -> bool {
+        use core::fmt::Write;
+
+        if Arc::ptr_eq(&self.ops, &other.ops) {
+            return true;
+        }
+        let expected = self.to_string();
+        let mut writer = MatchWriter { rest: &expected };
+        write!(writer, "{}", other).is_ok() && writer.rest.is_empty()
+    }
+}
+impl cmp::Eq for Code {}
+impl hash::Hash for Code {
+    fn hash<H: hash::Hasher>(&self, h: &mut H) {
+        h.write_u64(self.content_hash())
+    }
+}
+
+/// The location of a `code!` invocation, which produced a piece of [`Code`].
+///
+/// Source locations are compared by identity, so two invocations on the same
+/// line are distinct.
+///
+/// [`Code`]: struct.Code.html
 #[derive(Debug)]
 pub struct SourceLoc {
+    // Not a public API - use the accessor methods instead.
+    #[doc(hidden)]
     pub line: u32,
+    #[doc(hidden)]
     pub column: u32,
+    #[doc(hidden)]
     pub file: &'static str,
-    pub colour: AtomicUsize,
+    #[doc(hidden)]
+    pub template: TemplateCache,
+}
+
+impl SourceLoc {
+    /// The file containing the `code!` invocation.
+    pub fn file(&self) -> &'static str {
+        self.file
+    }
+
+    /// The line of the `code!` invocation, starting from 1.
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    /// The column of the `code!` invocation, starting from 1.
+    pub fn column(&self) -> u32 {
+        self.column
+    }
+
+    // Not a public API
+    #[doc(hidden)]
+    pub const fn new(line: u32, column: u32, file: &'static str) -> Self {
+        SourceLoc {
+            line,
+            column,
+            file,
+            template: TemplateCache::new(),
+        }
+    }
 }
 
 // NOTE: We want to compare SourceLoc objects by pointer, so we need custom hash
 // and eq definitions.
 impl cmp::PartialEq for SourceLoc {
     fn eq(&self, other: &Self) -> bool {
-        self as *const Self == other as *const Self
+        ptr::eq(self, other)
     }
 }
 impl cmp::Eq for SourceLoc {}
@@ -210,57 +622,13 @@ impl hash::Hash for SourceLoc {
     }
 }
 
-/// Simple helper function to count the number of instances of a particular
-/// character in a string. This is used to pre-allocate sufficiently large
-/// buffers.
-fn count_char(s: &str, c: char) -> usize {
-    let mut count = 0;
-    let mut idx = 0;
-    while let Some(x) = s[idx..].find(c) {
-        idx += x + c.len_utf8();
-        count += 1;
-    }
-    count
-}
-
-/// Calculate the lowest indent (in charcters) of any line in the input string.
-fn min_indent(s: &str) -> usize {
-    let mut min_indent = usize::max_value();
-    for line in s.lines() {
-        // If we have a blank line, ignore it.
-        let trimmed = line.trim_left();
-        if trimmed.is_empty() { continue; }
-
-        // Otherwise, indentation is the minimum of the length difference, and
-        // min_indent.
-        min_indent = usize::min(line.len() - trimmed.len(), min_indent);
-    }
-    min_indent
-}
-
-/// Find the next substitution point
-fn subst_point(s: &str) -> Option<(&str, &str, &str)> {
-    match s.find('$') {
-        Some(x) => {
-            let start = &s[..x];
-            let s = &s[x+1..];
-            let x = s.find(|x| match x {
-                'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => false,
-                _ => true,
-            }).unwrap_or(s.len());
-
-            Some((start, &s[..x], &s[x..]))
-        }
-        None => None,
-    }
-}
-
 // Not a public API
 #[doc(hidden)]
 pub struct BuildArg {
     name: &'static str,
-    code: Option<Code>,
+    op: Option<Op>,
     index: usize,
+    value: Option<FmtValue>,
 }
 
 impl BuildArg {
@@ -268,87 +636,95 @@ impl BuildArg {
     #[doc(hidden)]
     pub fn new<T: CodeArg>(name: &'static str, arg: T) -> Self {
         BuildArg {
-            name: name,
-            code: Some(arg.into_code()),
+            name,
+            value: arg.fmt_value(),
+            op: Some(Op::Inner(arg.into_code().ops)),
             index: 0,
         }
     }
-}
 
-fn get_by_name<'a>(name: &str, args: &'a mut [BuildArg]) -> &'a mut BuildArg {
-    for arg in args {
-        if arg.name == name {
-            return arg;
+    // Not a public API
+    #[doc(hidden)]
+    pub fn new_in<T: CodeArg>(
+        arena: &CodeArena,
+        name: &'static str,
+        arg: T,
+    ) -> Self {
+        BuildArg {
+            name,
+            value: arg.fmt_value(),
+            op: Some(arg.into_arena(arena).0),
+            index: 0,
         }
     }
-    panic!("No argument provided for substitution {}", name)
 }
 
-fn str_to_code<'a, F>(
-    tmpl: &'a str,
-    sourceloc: Option<&'static SourceLoc>,
-    mut args: Option<&mut [BuildArg]>,
-    mut str_op: F,
-) -> Code
-where
-    F: FnMut(&'a str) -> Op
-{
-    // Come up with a size estimate. This should mean that we never need to
-    // re-allocate our backing buffer.
-    let mut estimate = count_char(tmpl, '\n') * 2 + 1;
-    if args.is_some() {
-        estimate += count_char(tmpl, '$') * 2;
-    }
-    if sourceloc.is_some() {
-        estimate += 1;
-    }
-
-    let mut ops = Vec::with_capacity(estimate);
-    if let Some(sourceloc) = sourceloc {
-        ops.push(Op::SourceLoc(sourceloc));
-    }
-
-    let indent = min_indent(tmpl);
-
-    // NOTE: We use .split('\n') rather than .lines here because we want to
-    // handle the last newline correctly.
-    for (idx, mut line) in tmpl.split('\n').enumerate() {
-        if idx != 0 {
-            ops.push(Op::Nl);
+#[test]
+fn shared_fragments() {
+    let prelude = code!("
+        #include <stdint.h>
+        #include <stddef.h>
+    ");
+    let copy = prelude.clone();
+    assert!(Arc::ptr_eq(&prelude.ops, &copy.ops));
+
+    let res = code!("
+        $a
+        $b
+          $a",
+        a: prelude.clone(),
+        b: copy,
+    );
+    match (&res.ops[2], &res.ops[4]) {
+        (Op::Inner(a), Op::Inner(b)) => {
+            assert!(Arc::ptr_eq(a, &prelude.ops));
+            assert!(Arc::ptr_eq(b, &prelude.ops));
         }
+        _ => panic!("expected substituted fragments"),
+    }
+    assert_eq!(
+        res.to_string(),
+        "#include <stdint.h>\n#include <stddef.h>\n\n\
+         #include <stdint.h>\n#include <stddef.h>\n\n  \
+         #include <stdint.h>\n  #include <stddef.h>"
+    );
+}
 
-        // Remove any common indent prefix, and remove trailing whitespace.
-        if line.len() >= indent {
-            line = &line[indent..];
-        }
-        line = line.trim_right();
-        if line.is_empty() {
-            continue;
-        }
+#[cfg(feature = "rayon")]
+#[test]
+fn par_collect() {
+    use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
-        if let Some(ref mut args) = args {
-            while let Some((b, name, r)) = subst_point(line) {
-                line = r;
-                if !b.is_empty() {
-                    ops.push(str_op(b));
-                }
-
-                let arg = get_by_name(name, args);
-                if let Some(code) = arg.code.take() {
-                    arg.index = ops.len();
-                    ops.push(Op::Inner(code.ops.into_boxed_slice()));
-                } else {
-                    let off = ops.len() - arg.index;
-                    ops.push(Op::InnerRef(off));
-                }
-            }
-        }
-
-        if !line.is_empty() {
-            ops.push(str_op(line));
-        }
-    }
+    let code: Code = (0..1000).into_par_iter().map(|i| {
+        code!("case $i:\n", i: i)
+    }).collect();
+    let expected: Code = (0..1000).map(|i| code!("case $i:\n", i: i)).collect();
+    assert_eq!(code.to_string(), expected.to_string());
+}
 
-    debug_assert!(estimate >= ops.len());
-    Code { ops }
+#[test]
+fn render_equality() {
+    use core::hash::{Hash, Hasher};
+
+    let a = code!("
+        fn $name() {
+            $body
+        }",
+        name: "f",
+        body: "x();",
+    );
+    let b = code!("fn f() {\n    x();\n}");
+    let c = code!("fn f() {\n    y();\n}");
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+    assert_ne!(b, code!("fn f() {\n    x();\n}\nmore"));
+    assert_eq!(a.content_hash(), b.content_hash());
+    assert_ne!(a.content_hash(), c.content_hash());
+
+    let hash = |code: &Code| {
+        let mut h = ::std::collections::hash_map::DefaultHasher::new();
+        code.hash(&mut h);
+        h.finish()
+    };
+    assert_eq!(hash(&a), hash(&b));
 }
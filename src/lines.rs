@@ -0,0 +1,281 @@
+//! This module provides line-oriented introspection of rendered `Code`.
+
+use {display, Code, SourceLoc};
+
+use alloc::string::String;
+use alloc::vec::{self, Vec};
+use core::fmt;
+
+/// A single line of rendered code, produced by [`Code::lines`].
+///
+/// [`Code::lines`]: struct.Code.html#method.lines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub(crate) indent: usize,
+    pub(crate) text: String,
+    pub(crate) source_loc: Option<&'static SourceLoc>,
+}
+
+impl Line {
+    pub(crate) fn blank() -> Self {
+        Line {
+            indent: 0,
+            text: String::new(),
+            source_loc: None,
+        }
+    }
+
+    /// The number of spaces of indentation at the start of the line.
+    pub fn indent(&self) -> usize {
+        self.indent
+    }
+
+    /// The text of the line, excluding indentation. Leading whitespace other
+    /// than spaces, such as tabs, is part of the text. This is empty for blank
+    /// lines.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The `code!` invocation which produced the first non-whitespace
+    /// character of the line, if any. This is `None` for blank lines.
+    pub fn source_loc(&self) -> Option<&'static SourceLoc> {
+        self.source_loc
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:indent$}{}", "", self.text, indent = self.indent)
+    }
+}
+
+/// An iterator over the rendered lines of [`Code`], produced by
+/// [`Code::lines`].
+///
+/// [`Code`]: struct.Code.html
+/// [`Code::lines`]: struct.Code.html#method.lines
+#[derive(Debug, Clone)]
+pub struct Lines {
+    inner: vec::IntoIter<Line>,
+}
+
+impl Iterator for Lines {
+    type Item = Line;
+
+    fn next(&mut self) -> Option<Line> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Lines {
+    fn next_back(&mut self) -> Option<Line> {
+        self.inner.next_back()
+    }
+}
+
+impl ExactSizeIterator for Lines {}
+
+/// The size of the code produced by a single `code!` invocation, as part of
+/// [`Stats`].
+///
+/// [`Stats`]: struct.Stats.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SiteStats {
+    source_loc: Option<&'static SourceLoc>,
+    lines: usize,
+    bytes: usize,
+}
+
+impl SiteStats {
+    /// The `code!` invocation, or `None` for blank lines and lines which
+    /// weren't produced by `code!`.
+    pub fn source_loc(&self) -> Option<&'static SourceLoc> {
+        self.source_loc
+    }
+
+    /// The number of lines attributed to this invocation.
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /// The number of bytes in the lines attributed to this invocation,
+    /// including newlines.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+/// The size of rendered [`Code`], broken down by the `code!` invocation which
+/// produced each line. Produced by [`Code::stats`].
+///
+/// Each line is attributed to the invocation which produced its first
+/// non-whitespace character. The `Display` implementation writes a report with
+/// one row per invocation, ordered by source location.
+///
+/// [`Code`]: struct.Code.html
+/// [`Code::stats`]: struct.Code.html#method.stats
+#[derive(Debug, Clone)]
+pub struct Stats {
+    sites: Vec<SiteStats>,
+}
+
+impl Stats {
+    /// The statistics for each `code!` invocation, in the order in which they
+    /// first produced a line.
+    pub fn sites(&self) -> &[SiteStats] {
+        &self.sites
+    }
+
+    /// The total number of lines.
+    pub fn lines(&self) -> usize {
+        self.sites.iter().map(|s| s.lines).sum()
+    }
+
+    /// The total number of bytes, which is the length of the rendered code.
+    pub fn bytes(&self) -> usize {
+        self.sites.iter().map(|s| s.bytes).sum()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sites: Vec<_> = self.sites.iter().collect();
+        sites.sort_by_key(|s| match s.source_loc {
+            Some(loc) => (0, loc.file, loc.line, loc.column),
+            None => (1, "", 0, 0),
+        });
+        for site in sites {
+            match site.source_loc {
+                Some(loc) => {
+                    write!(f, "{}:{}:{}", loc.file, loc.line, loc.column)?
+                }
+                None => f.write_str("<other>")?,
+            }
+            writeln!(f, ": {} lines, {} bytes", site.lines, site.bytes)?;
+        }
+        write!(f, "total: {} lines, {} bytes", self.lines(), self.bytes())
+    }
+}
+
+/// Checks whether anything is written, failing as soon as it is.
+struct EmptyWriter;
+
+impl fmt::Write for EmptyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if s.is_empty() { Ok(()) } else { Err(fmt::Error) }
+    }
+}
+
+impl Code {
+    /// Iterate over the lines of this code as it is rendered by its `Display`
+    /// implementation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use] extern crate cghelper;
+    /// # fn main() {
+    /// let body = code!("return 0;");
+    /// let func = code!("
+    ///     int main() {
+    ///         $body
+    ///     }",
+    ///     body: body.clone(),
+    /// );
+    /// assert_eq!(body.lines().len(), 1);
+    ///
+    /// let line = func.lines().nth(1).unwrap();
+    /// assert_eq!((line.indent(), line.text()), (4, "return 0;"));
+    ///
+    /// // The line came from the `code!` invocation which built `body`.
+    /// let origin = body.lines().next().unwrap().source_loc();
+    /// assert_eq!(line.source_loc(), origin);
+    /// # }
+    /// ```
+    pub fn lines(&self) -> Lines {
+        Lines {
+            inner: display::collect_lines(&self.ops).into_iter(),
+        }
+    }
+
+    /// Check whether this code renders to an empty string. Whitespace-only
+    /// lines are never rendered, so code containing only whitespace is empty.
+    pub fn is_empty(&self) -> bool {
+        use core::fmt::Write;
+
+        write!(EmptyWriter, "{}", self).is_ok()
+    }
+
+    /// Compute the number of lines and bytes produced by each `code!`
+    /// invocation within this code.
+    pub fn stats(&self) -> Stats {
+        let mut sites: Vec<SiteStats> = Vec::new();
+        let mut lines = self.lines().peekable();
+        while let Some(line) = lines.next() {
+            let loc = line.source_loc;
+            let idx = match sites.iter().position(|s| s.source_loc == loc) {
+                Some(idx) => idx,
+                None => {
+                    sites.push(SiteStats {
+                        source_loc: loc,
+                        lines: 0,
+                        bytes: 0,
+                    });
+                    sites.len() - 1
+                }
+            };
+            let newline = if lines.peek().is_some() { 1 } else { 0 };
+            sites[idx].lines += 1;
+            sites[idx].bytes += line.indent + line.text.len() + newline;
+        }
+        Stats { sites }
+    }
+}
+
+#[test]
+fn lines_test() {
+    use alloc::string::ToString;
+
+    let inner = code!("a;\n\n\nb;");
+    let code = code!("
+        {
+            $inner
+        }
+
+        c;",
+        inner: inner,
+    );
+    let lines: Vec<_> = code.lines().map(|l| l.to_string()).collect();
+    assert_eq!(lines, ["{", "    a;", "", "    b;", "}", "", "c;"]);
+    assert_eq!(lines.join("\n"), code.to_string());
+
+    let origins: Vec<_> = code.lines().map(|l| l.source_loc()).collect();
+    assert_eq!(origins[0], origins[4]);
+    assert_eq!(origins[1], origins[3]);
+    assert!(origins[0] != origins[1]);
+    assert_eq!(origins[2], None);
+
+    let stats = code.stats();
+    assert_eq!(stats.lines(), 7);
+    assert_eq!(stats.bytes(), code.to_string().len());
+    let counts: Vec<_> = stats.sites().iter().map(|s| s.lines()).collect();
+    assert_eq!(counts, [3, 2, 2]);
+
+    // Only spaces are counted as indentation.
+    let tabs = code!("a {\n\tb;\n}");
+    let lines: Vec<_> = tabs.lines().collect();
+    assert_eq!((lines[1].indent(), lines[1].text()), (0, "\tb;"));
+    let lines: Vec<_> = tabs.lines().map(|l| l.to_string()).collect();
+    assert_eq!(lines.join("\n"), tabs.to_string());
+    let mixed = code!("{\n    $x\n}", x: ::KeepIndent(" \t y"));
+    let lines: Vec<_> = mixed.lines().map(|l| l.to_string()).collect();
+    assert_eq!(lines.join("\n"), mixed.to_string());
+
+    assert!(code!("   \n\n  ").is_empty());
+    assert!(!code.is_empty());
+}
@@ -0,0 +1,147 @@
+//! This module provides `EmitOnce` and `EmitAnchor`, which allow definitions
+//! required by many fragments of code to be emitted only once.
+
+use {Code, CodeArg, Op};
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+/// A definition shared between every reference to the same key.
+#[derive(Debug)]
+pub(crate) struct OnceDef {
+    pub(crate) key: Box<str>,
+    pub(crate) code: Code,
+}
+
+/// A reference to a definition, such as a helper function, which must be
+/// emitted exactly once in the generated code.
+///
+/// When substituted into a template, an `EmitOnce` is rendered as its key,
+/// which is typically the name of the definition. The definition itself is
+/// emitted at the [`EmitAnchor`] in the code being rendered, or at the start
+/// of the code if there is no anchor. Each key is emitted only once, using the
+/// first definition registered for it, no matter how deeply the references
+/// to it are nested.
+///
+/// Definitions may themselves contain `EmitOnce` references, in which case
+/// the definitions they depend on are emitted first.
+///
+/// [`EmitAnchor`]: struct.EmitAnchor.html
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::{EmitAnchor, EmitOnce};
+/// # fn main() {
+/// let swap = EmitOnce::new("swap_u32", code!("
+///     static inline void swap_u32(uint32_t* a, uint32_t* b) {
+///         uint32_t t = *a; *a = *b; *b = t;
+///     }"));
+/// let sort_a = code!("void sort_a() { $swap(&x, &y); }", swap: swap.clone());
+/// let sort_b = code!("void sort_b() { $swap(&z, &w); }", swap: swap);
+///
+/// let file = code!("
+///     #include <stdint.h>
+///
+///     $helpers
+///
+///     $a
+///     $b",
+///     helpers: EmitAnchor,
+///     a: sort_a,
+///     b: sort_b,
+/// );
+/// assert_eq!(file.to_string(), "\
+/// #include <stdint.h>
+///
+/// static inline void swap_u32(uint32_t* a, uint32_t* b) {
+///     uint32_t t = *a; *a = *b; *b = t;
+/// }
+///
+/// void sort_a() { swap_u32(&x, &y); }
+/// void sort_b() { swap_u32(&z, &w); }");
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct EmitOnce {
+    def: Arc<OnceDef>,
+}
+
+impl EmitOnce {
+    /// Create a reference to `definition`, which is emitted once for each
+    /// distinct `key`.
+    pub fn new<K: Into<String>, T: CodeArg>(key: K, definition: T) -> Self {
+        EmitOnce {
+            def: Arc::new(OnceDef {
+                key: key.into().into_boxed_str(),
+                code: definition.into_code(),
+            }),
+        }
+    }
+}
+
+impl CodeArg for EmitOnce {
+    fn into_code(self) -> Code {
+        Code::from_ops(vec![ Op::EmitOnce(self.def) ])
+    }
+}
+
+/// The location at which the definitions referenced by [`EmitOnce`] objects
+/// are emitted, separated by blank lines.
+///
+/// Only the first anchor in the code being rendered is used.
+///
+/// [`EmitOnce`]: struct.EmitOnce.html
+#[derive(Debug, Clone, Copy)]
+pub struct EmitAnchor;
+
+impl CodeArg for EmitAnchor {
+    fn into_code(self) -> Code {
+        Code::from_ops(vec![ Op::EmitAnchor ])
+    }
+}
+
+#[test]
+fn emit_once_test() {
+    use alloc::string::ToString;
+
+    let base = EmitOnce::new("base", code!("int base;"));
+    let derived = EmitOnce::new("derived", code!(
+        "int derived = $base;",
+        base: base.clone(),
+    ));
+    let body = code!("
+        f($d, $b);
+        {
+            g($d);
+        }",
+        d: derived,
+        b: base,
+    );
+
+    // Without an anchor, definitions are emitted at the start, with their
+    // dependencies first.
+    assert_eq!(
+        body.to_string(),
+        "int base;\n\nint derived = base;\n\n\
+         f(derived, base);\n{\n    g(derived);\n}"
+    );
+
+    let file = code!("
+        {
+            $helpers
+        }
+        $body
+        $again",
+        helpers: EmitAnchor,
+        body: body,
+        again: EmitAnchor,
+    );
+    assert_eq!(
+        file.to_string(),
+        "{\n    int base;\n\n    int derived = base;\n}\n\n\
+         f(derived, base);\n{\n    g(derived);\n}"
+    );
+}
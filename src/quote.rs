@@ -0,0 +1,202 @@
+//! Wrappers which quote a runtime string as a string literal in some target
+//! language.
+
+use {Code, CodeArg, Op};
+
+use alloc::string::ToString;
+use core::fmt::{self, Write};
+
+macro_rules! quote_wrapper {($($i:ident),*) => {
+    $( impl<S: AsRef<str>> CodeArg for $i<S> {
+        fn into_code(self) -> Code {
+            // The escaped literal never contains a raw newline, so it can be
+            // stored directly.
+            Code::from_ops(vec![ Op::Blob(self.to_string().into_boxed_str()) ])
+        }
+    } )*
+}}
+quote_wrapper! { CStr, RustStr, JsonStr, PyStr }
+
+impl<S: AsRef<str>> CodeArg for ShellWord<S> {
+    fn into_code(self) -> Code {
+        // Newlines can't be escaped within single quotes, so a word containing
+        // them is emitted as raw text, which isn't re-indented.
+        let word = self.to_string().into_boxed_str();
+        if word.contains('\n') {
+            Code::from_ops(vec![ Op::RawBlob(word) ])
+        } else {
+            Code::from_ops(vec![ Op::Blob(word) ])
+        }
+    }
+}
+
+/// Quote a string as a C (or C++) string literal.
+///
+/// Characters outside of printable ASCII are written as octal escapes of their
+/// UTF-8 encoding, and `?` is escaped where it could form a trigraph.
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::CStr;
+/// # fn main() {
+/// let msg = "Hello, \"World\"\n";
+/// let res = code!("puts($msg);", msg: CStr(msg));
+/// assert_eq!(res.to_string(), r#"puts("Hello, \"World\"\n");"#);
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct CStr<S>(pub S);
+
+impl<S: AsRef<str>> fmt::Display for CStr<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        let mut prev = '\0';
+        for c in self.0.as_ref().chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                '?' if prev == '?' => f.write_str("\\?")?,
+                ' '..='~' => f.write_char(c)?,
+                _ => {
+                    // NOTE: Octal escapes are at most 3 digits long, so unlike
+                    // hex escapes they can't absorb a following digit.
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        write!(f, "\\{:03o}", b)?;
+                    }
+                }
+            }
+            prev = c;
+        }
+        f.write_char('"')
+    }
+}
+
+/// Quote a string as a Rust string literal.
+#[derive(Debug, Clone, Copy)]
+pub struct RustStr<S>(pub S);
+
+impl<S: AsRef<str>> fmt::Display for RustStr<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The `Debug` implementation for `str` produces a valid literal.
+        write!(f, "{:?}", self.0.as_ref())
+    }
+}
+
+/// Quote a string as a JSON string, which is also a valid JavaScript string
+/// literal.
+#[derive(Debug, Clone, Copy)]
+pub struct JsonStr<S>(pub S);
+
+impl<S: AsRef<str>> fmt::Display for JsonStr<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.as_ref().chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                '\u{8}' => f.write_str("\\b")?,
+                '\u{c}' => f.write_str("\\f")?,
+                // U+2028 and U+2029 are valid in JSON, but end lines in older
+                // JavaScript engines.
+                '\0'..='\u{1f}' | '\u{2028}' | '\u{2029}' => {
+                    write!(f, "\\u{:04x}", c as u32)?
+                }
+                _ => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// Quote a string as a Python 3 string literal.
+#[derive(Debug, Clone, Copy)]
+pub struct PyStr<S>(pub S);
+
+impl<S: AsRef<str>> fmt::Display for PyStr<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.as_ref().chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                '\0'..='\u{1f}' | '\u{7f}' => write!(f, "\\x{:02x}", c as u32)?,
+                _ => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// Quote a string as a single word for a POSIX shell.
+///
+/// Words consisting only of characters which are never special to the shell
+/// are emitted unchanged, and all others are wrapped in single quotes. Any
+/// newlines are kept within the quotes, and the lines after them are not
+/// indented, so that the word's value is unchanged.
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::ShellWord;
+/// # fn main() {
+/// let res = code!("cp $a $b", a: ShellWord("a.txt"), b: ShellWord("it's here"));
+/// assert_eq!(res.to_string(), r#"cp a.txt 'it'\''s here'"#);
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct ShellWord<S>(pub S);
+
+impl<S: AsRef<str>> fmt::Display for ShellWord<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.0.as_ref();
+        let safe = !s.is_empty() && s.chars().all(|c| matches!(c,
+            'a'..='z' | 'A'..='Z' | '0'..='9' |
+            '_' | '@' | '%' | '+' | '=' | ':' | ',' | '.' | '/' | '-'
+        ));
+        if safe {
+            return f.write_str(s);
+        }
+
+        f.write_char('\'')?;
+        for (idx, part) in s.split('\'').enumerate() {
+            if idx != 0 {
+                f.write_str("'\\''")?;
+            }
+            f.write_str(part)?;
+        }
+        f.write_char('\'')
+    }
+}
+
+#[test]
+fn quote_test() {
+    let s = "a\"b\\c\nd\té??=\u{1}";
+    assert_eq!(CStr(s).to_string(), r#""a\"b\\c\nd\t\303\251?\?=\001""#);
+    assert_eq!(RustStr(s).to_string(), r#""a\"b\\c\nd\té??=\u{1}""#);
+    assert_eq!(JsonStr(s).to_string(), r#""a\"b\\c\nd\té??=\u0001""#);
+    assert_eq!(PyStr(s).to_string(), r#""a\"b\\c\nd\té??=\x01""#);
+    assert_eq!(ShellWord(s).to_string(), "'a\"b\\c\nd\té??=\u{1}'");
+    assert_eq!(ShellWord("").to_string(), "''");
+
+    let res = code!("
+        if true; then
+            echo $word
+        fi",
+        word: ShellWord("line1\n  line2"),
+    );
+    assert_eq!(res.to_string(), "if true; then\n    echo 'line1\n  line2'\nfi");
+    assert!(res.lines().all(|line| !line.text().contains('\n')));
+}
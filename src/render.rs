@@ -0,0 +1,164 @@
+//! Options controlling how `Code` is rendered.
+
+use {display, Code};
+
+use core::fmt;
+#[cfg(feature = "std")]
+use std::env;
+#[cfg(feature = "std")]
+use std::io::{self, IsTerminal};
+#[cfg(feature = "std")]
+use alloc::string::String;
+
+/// How the source location of each piece of code is highlighted when
+/// rendering.
+///
+/// The colour modes require the `highlight` feature, which is enabled by
+/// default. If it is disabled, they fall back to `Markers`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Highlight {
+    /// Don't highlight code. This is the same as the `Display` implementation
+    /// on [`Code`].
+    ///
+    /// [`Code`]: struct.Code.html
+    None,
+    /// Pick a mode based on the environment. This is the mode used by the
+    /// alternate `Debug` implementation on [`Code`].
+    ///
+    /// * If `NO_COLOR` is set, use `Markers`.
+    /// * If `CLICOLOR_FORCE` is set to anything other than `0`, use colours
+    ///   even if the output is not a terminal.
+    /// * If either stdout or stderr is not a terminal, or `TERM` is `dumb`,
+    ///   use `Markers`. Both are checked, as `Debug` output is often written
+    ///   to stderr, such as by `dbg!`.
+    /// * If `TERM` indicates 256 colour support, or `COLORTERM` is set, use
+    ///   `Ansi256`.
+    /// * Otherwise, use `Ansi16`.
+    ///
+    /// Without the `std` feature, this always uses `Markers`.
+    ///
+    /// [`Code`]: struct.Code.html
+    Auto,
+    /// Use 256-colour ANSI escape sequences.
+    Ansi256,
+    /// Use only the basic 16-colour ANSI escape sequences.
+    Ansi16,
+    /// Don't use colour, and instead wrap the code from each source location
+    /// in plain text markers like `«file.rs:10|...»`.
+    Markers,
+}
+
+#[cfg(feature = "std")]
+fn env_set(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+impl Highlight {
+    /// Resolve `Auto` to a concrete highlighting mode. Colour modes are
+    /// replaced with `Markers` if the `highlight` feature is disabled.
+    pub(crate) fn resolve(self) -> Highlight {
+        match self {
+            Highlight::None | Highlight::Markers => self,
+            _ if !cfg!(feature = "highlight") => Highlight::Markers,
+            Highlight::Auto => Highlight::detect(),
+            _ => self,
+        }
+    }
+
+    /// Pick a highlighting mode based on the environment.
+    #[cfg(feature = "std")]
+    fn detect() -> Highlight {
+        if env_set("NO_COLOR").is_some() {
+            return Highlight::Markers;
+        }
+        let forced = env_set("CLICOLOR_FORCE").is_some_and(|v| v != "0");
+        let term = env_set("TERM").unwrap_or_default();
+        let terminal = io::stdout().is_terminal() && io::stderr().is_terminal();
+        if !forced && (!terminal || term == "dumb") {
+            return Highlight::Markers;
+        }
+        if term.contains("256color") || env_set("COLORTERM").is_some() {
+            Highlight::Ansi256
+        } else {
+            Highlight::Ansi16
+        }
+    }
+
+    /// Without `std`, the environment can't be inspected, so never use colour.
+    #[cfg(not(feature = "std"))]
+    fn detect() -> Highlight {
+        Highlight::Markers
+    }
+}
+
+/// Options which control how [`Code`] is rendered by [`Code::render`].
+///
+/// [`Code`]: struct.Code.html
+/// [`Code::render`]: struct.Code.html#method.render
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::{Highlight, RenderOptions};
+/// # fn main() {
+/// let code = code!("$x;", x: "a");
+/// let opts = RenderOptions::new().highlight(Highlight::Markers);
+/// let rendered = code.render(&opts).to_string();
+/// assert!(rendered.starts_with("«src/render.rs:"));
+/// assert!(rendered.contains("|a;»"));
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderOptions {
+    highlight: Highlight,
+}
+
+impl RenderOptions {
+    /// The default options, which render code in the same way as the `Display`
+    /// implementation on `Code`.
+    pub fn new() -> Self {
+        RenderOptions {
+            highlight: Highlight::None,
+        }
+    }
+
+    /// Set how the source location of each piece of code is highlighted. If
+    /// this is not `Highlight::None`, a legend of source locations is written
+    /// after the code.
+    pub fn highlight(mut self, highlight: Highlight) -> Self {
+        self.highlight = highlight;
+        self
+    }
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions::new()
+    }
+}
+
+/// The result of [`Code::render`]. Use the `Display` implementation on this
+/// type to write the rendered code.
+///
+/// [`Code::render`]: struct.Code.html#method.render
+pub struct Rendered<'a> {
+    code: &'a Code,
+    options: RenderOptions,
+}
+
+impl Code {
+    /// Render this code using the given options.
+    pub fn render(&self, options: &RenderOptions) -> Rendered<'_> {
+        Rendered {
+            code: self,
+            options: *options,
+        }
+    }
+}
+
+impl<'a> fmt::Display for Rendered<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        display::do_display(&self.code.ops, None, f, 0, self.options.highlight)
+    }
+}
@@ -0,0 +1,284 @@
+//! This module provides format specs for substitutions such as `${val:#x}`,
+//! which are applied to numbers and single lines of text when binding a
+//! template.
+
+use alloc::string::String;
+use core::fmt::LowerExp;
+
+/// The alignment of a value within its width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// A parsed format spec, using the same syntax as `format!`:
+/// `[[fill]align][+][#][0][width][.precision][type]`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Spec {
+    fill: char,
+    align: Option<Align>,
+    plus: bool,
+    alt: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    kind: Option<char>,
+}
+
+// Not a public API
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy)]
+pub enum FmtValue {
+    /// A signed integer, with the number of bits in its type.
+    Signed(i128, u32),
+    /// An unsigned integer, with the number of bits in its type.
+    Unsigned(u128, u32),
+    /// A float, and whether it is an `f32`.
+    Float(f64, bool),
+}
+
+/// Render a float as a literal, rounding it to an `f32` first if `single` is
+/// set.
+pub(crate) fn float_literal(v: f64, single: bool) -> String {
+    // `Display` renders `1.0` as `1`, which many target languages would treat
+    // as an integer literal. `Debug` always produces a literal which
+    // round-trips, and includes a `.0` or exponent.
+    //
+    // NaN and the infinities have no literal syntax, so we name them in the
+    // style of C's `<math.h>` instead.
+    if v.is_nan() {
+        "NAN".into()
+    } else if v.is_infinite() {
+        if v > 0.0 { "INFINITY" } else { "-INFINITY" }.into()
+    } else if single {
+        format!("{:?}", v as f32)
+    } else {
+        format!("{:?}", v)
+    }
+}
+
+fn align(c: char) -> Option<Align> {
+    match c {
+        '<' => Some(Align::Left),
+        '^' => Some(Align::Center),
+        '>' => Some(Align::Right),
+        _ => None,
+    }
+}
+
+/// Parse a decimal number from the start of `s`, returning it and the rest of
+/// the string.
+fn number(s: &str) -> (Option<usize>, &str) {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    match s[..end].parse() {
+        Ok(n) => (Some(n), &s[end..]),
+        Err(_) => (None, s),
+    }
+}
+
+impl Spec {
+    /// Parse a format spec, panicking if it is invalid.
+    pub(crate) fn parse(spec: &str) -> Spec {
+        let invalid = || -> ! { panic!("Invalid format spec `{}`", spec) };
+
+        let mut res = Spec {
+            fill: ' ',
+            align: None,
+            plus: false,
+            alt: false,
+            zero: false,
+            width: 0,
+            precision: None,
+            kind: None,
+        };
+
+        let mut s = spec;
+        let mut chars = s.chars();
+        let fill = chars.next();
+        if let (Some(fill), Some(a)) = (fill, chars.next().and_then(align)) {
+            res.fill = fill;
+            res.align = Some(a);
+            s = chars.as_str();
+        } else if let Some(a) = s.chars().next().and_then(align) {
+            res.align = Some(a);
+            s = &s[1..];
+        }
+        if s.starts_with('+') {
+            res.plus = true;
+            s = &s[1..];
+        }
+        if s.starts_with('#') {
+            res.alt = true;
+            s = &s[1..];
+        }
+        if s.starts_with('0') {
+            res.zero = true;
+            s = &s[1..];
+        }
+        let (width, rest) = number(s);
+        res.width = width.unwrap_or(0);
+        s = rest;
+        if s.starts_with('.') {
+            let (precision, rest) = number(&s[1..]);
+            res.precision = Some(precision.unwrap_or_else(|| invalid()));
+            s = rest;
+        }
+
+        let mut chars = s.chars();
+        res.kind = chars.next();
+        match res.kind {
+            None | Some('x') | Some('X') | Some('o') | Some('b') |
+            Some('e') | Some('E') if chars.as_str().is_empty() => res,
+            _ => invalid(),
+        }
+    }
+
+    /// Format a number according to this spec.
+    pub(crate) fn number(&self, value: FmtValue) -> String {
+        let (neg, body, prefix) = match value {
+            FmtValue::Signed(v, bits) => {
+                match self.kind {
+                    None => (v < 0, format!("{}", v.unsigned_abs()), ""),
+                    Some('e') | Some('E') => {
+                        (v < 0, self.exp(v.unsigned_abs()), "")
+                    }
+                    // Like `format!`, negative numbers are shown in two's
+                    // complement in any base other than decimal.
+                    Some(_) => {
+                        let mask = u128::MAX >> (128 - bits);
+                        self.radix(v as u128 & mask)
+                    }
+                }
+            }
+            FmtValue::Unsigned(v, _) => match self.kind {
+                None => (false, format!("{}", v), ""),
+                Some('e') | Some('E') => (false, self.exp(v), ""),
+                Some(_) => self.radix(v),
+            },
+            FmtValue::Float(v, single) => {
+                let neg = v.is_sign_negative() && !v.is_nan();
+                let abs = v.abs();
+                let body = match (self.kind, self.precision, single) {
+                    _ if !abs.is_finite() => float_literal(abs, single),
+                    (None, None, _) => float_literal(abs, single),
+                    (None, Some(p), false) => format!("{:.*}", p, abs),
+                    (None, Some(p), true) => format!("{:.*}", p, abs as f32),
+                    (Some('e'), _, false) | (Some('E'), _, false) => {
+                        self.exp(abs)
+                    }
+                    (Some('e'), _, true) | (Some('E'), _, true) => {
+                        self.exp(abs as f32)
+                    }
+                    (Some(kind), _, _) => {
+                        panic!("Format type `{}` can't be used with floats",
+                               kind)
+                    }
+                };
+                (neg, body, "")
+            }
+        };
+
+        let sign = if neg { "-" } else if self.plus { "+" } else { "" };
+        let len = sign.len() + prefix.len() + body.len();
+        if self.zero && len < self.width {
+            let mut res = String::with_capacity(self.width);
+            res.push_str(sign);
+            res.push_str(prefix);
+            res.extend((len..self.width).map(|_| '0'));
+            res.push_str(&body);
+            return res;
+        }
+        self.pad(&format!("{}{}{}", sign, prefix, body), Align::Right)
+    }
+
+    /// Format a number in scientific notation, with an upper case `E` if the
+    /// spec's type is `E`.
+    fn exp<T: LowerExp>(&self, v: T) -> String {
+        let body = match self.precision {
+            Some(p) => format!("{:.*e}", p, v),
+            None => format!("{:e}", v),
+        };
+        if self.kind == Some('E') { body.to_uppercase() } else { body }
+    }
+
+    /// Format an integer in the base given by the spec's type, returning it
+    /// along with its `#` prefix.
+    fn radix(&self, v: u128) -> (bool, String, &'static str) {
+        let (body, prefix) = match self.kind {
+            Some('x') => (format!("{:x}", v), "0x"),
+            Some('X') => (format!("{:X}", v), "0x"),
+            Some('o') => (format!("{:o}", v), "0o"),
+            Some('b') => (format!("{:b}", v), "0b"),
+            _ => panic!("Format type `{}` can't be used with integers",
+                        self.kind.unwrap_or(' ')),
+        };
+        (false, body, if self.alt { prefix } else { "" })
+    }
+
+    /// Format a single line of text according to this spec.
+    pub(crate) fn text(&self, text: &str) -> String {
+        if let Some(kind) = self.kind {
+            panic!("Format type `{}` can't be used with text", kind);
+        }
+        match self.precision {
+            Some(p) => match text.char_indices().nth(p) {
+                Some((end, _)) => self.pad(&text[..end], Align::Left),
+                None => self.pad(text, Align::Left),
+            },
+            None => self.pad(text, Align::Left),
+        }
+    }
+
+    /// Pad `s` to the spec's width, using `default` if no alignment was given.
+    fn pad(&self, s: &str, default: Align) -> String {
+        let len = s.chars().count();
+        if len >= self.width {
+            return s.into();
+        }
+        let fill = self.width - len;
+        let before = match self.align.unwrap_or(default) {
+            Align::Left => 0,
+            Align::Center => fill / 2,
+            Align::Right => fill,
+        };
+        let mut res = String::with_capacity(s.len() + fill);
+        res.extend((0..before).map(|_| self.fill));
+        res.push_str(s);
+        res.extend((before..fill).map(|_| self.fill));
+        res
+    }
+}
+
+#[test]
+fn format_specs() {
+    let num = |spec: &str, v: FmtValue| Spec::parse(spec).number(v);
+    assert_eq!(num("#x", FmtValue::Unsigned(255, 8)), "0xff");
+    assert_eq!(num("#010X", FmtValue::Unsigned(255, 8)), "0x000000FF");
+    assert_eq!(num("x", FmtValue::Signed(-1, 16)), "ffff");
+    assert_eq!(num("04", FmtValue::Signed(-7, 32)), "-007");
+    assert_eq!(num("+", FmtValue::Signed(7, 32)), "+7");
+    assert_eq!(num("*^7b", FmtValue::Unsigned(5, 32)), "**101**");
+    assert_eq!(num(".2", FmtValue::Float(-1.005, false)), "-1.00");
+    assert_eq!(num("", FmtValue::Float(0.1f32 as f64, true)), "0.1");
+    assert_eq!(num(".1E", FmtValue::Float(1500.0, false)), "1.5E3");
+    assert_eq!(num(">8", FmtValue::Float(1.0, false)), "     1.0");
+    assert_eq!(num("e", FmtValue::Float(0.1f32 as f64, true)), "1e-1");
+    assert_eq!(num("+", FmtValue::Float(f64::NAN, false)), "+NAN");
+    assert_eq!(num("", FmtValue::Float(f64::NEG_INFINITY, false)), "-INFINITY");
+    assert_eq!(num("e", FmtValue::Signed(-1500, 32)), "-1.5e3");
+    assert_eq!(num(".2E", FmtValue::Unsigned(7, 8)), "7.00E0");
+
+    let text = |spec: &str, s: &str| Spec::parse(spec).text(s);
+    assert_eq!(text("<6", "ab"), "ab    ");
+    assert_eq!(text(">6", "ab"), "    ab");
+    assert_eq!(text("-^6.3", "abcdef"), "-abc--");
+    assert_eq!(text("1", "abc"), "abc");
+}
+
+#[test]
+#[should_panic(expected = "Format type `x` can't be used with floats")]
+fn float_radix() {
+    Spec::parse("x").number(FmtValue::Float(1.0, false));
+}
@@ -0,0 +1,601 @@
+//! This module provides parsing of `code!` templates, and the per-call-site
+//! cache of parsed templates.
+
+use {BuildArg, Code, Op, SourceLoc};
+use arena::export;
+use display::render_text;
+use filter::{Filtered, Filters};
+use spec::Spec;
+use visit::text_op;
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicPtr, Ordering};
+#[cfg(test)]
+use alloc::string::ToString;
+
+/// Simple helper function to count the number of instances of a particular
+/// character in a string. This is used to pre-allocate sufficiently large
+/// buffers.
+fn count_char(s: &str, c: char) -> usize {
+    let mut count = 0;
+    let mut idx = 0;
+    while let Some(x) = s[idx..].find(c) {
+        idx += x + c.len_utf8();
+        count += 1;
+    }
+    count
+}
+
+/// Calculate the lowest indent (in charcters) of any line in the input string.
+fn min_indent(s: &str) -> usize {
+    let mut min_indent = usize::MAX;
+    for line in s.lines() {
+        // If we have a blank line, ignore it.
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() { continue; }
+
+        // Otherwise, indentation is the minimum of the length difference, and
+        // min_indent.
+        min_indent = usize::min(line.len() - trimmed.len(), min_indent);
+    }
+    min_indent
+}
+
+/// A substitution point found within a line of a template.
+struct SubstPoint<'a> {
+    before: &'a str,
+    name: &'a str,
+    filters: &'a str,
+    spec: Option<&'a str>,
+    after: &'a str,
+}
+
+/// Find the next substitution point, either `$name`, or `${name}` followed by
+/// any number of `|filter`s and an optional `:spec`.
+fn subst_point(s: &str) -> Option<SubstPoint<'_>> {
+    match s.find('$') {
+        Some(x) => {
+            let before = &s[..x];
+            let s = &s[x+1..];
+            if s.starts_with('{') {
+                let end = s.find('}')
+                    .expect("`${` without a matching `}`");
+                let (head, spec) = match s[1..end].find(':') {
+                    Some(colon) => (&s[1..colon+1], Some(&s[colon+2..end])),
+                    None => (&s[1..end], None),
+                };
+                let (name, filters) = match head.find('|') {
+                    Some(bar) => (&head[..bar], &head[bar+1..]),
+                    None => (head, ""),
+                };
+                let after = &s[end+1..];
+                return Some(SubstPoint { before, name, filters, spec, after });
+            }
+
+            let x = s.find(|x| !matches!(x,
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '_'
+            )).unwrap_or(s.len());
+
+            let (name, after) = s.split_at(x);
+            Some(SubstPoint { before, name, filters: "", spec: None, after })
+        }
+        None => None,
+    }
+}
+
+fn arg_index(name: &str, args: &[BuildArg]) -> usize {
+    // Positional arguments have an empty name, so can only be referred to by
+    // their index.
+    let idx = match name.parse::<usize>() {
+        Ok(idx) => Some(idx).filter(|&idx| idx < args.len()),
+        Err(_) if name.is_empty() => None,
+        Err(_) => args.iter().position(|arg| arg.name == name),
+    };
+    match idx {
+        Some(idx) => idx,
+        None => panic!("No argument provided for substitution {}", name),
+    }
+}
+
+/// A single piece of a parsed template.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Piece<'a> {
+    /// A newline character
+    Nl,
+    /// A chunk of text containing no newlines.
+    Text(&'a str),
+    /// A substitution of the argument with the given name and index, with an
+    /// optional transformation of its text.
+    Arg(&'a str, usize, Option<Transform<'a>>),
+    /// Text which is emitted exactly as written, and may contain newlines.
+    Raw(&'a str),
+}
+
+/// The filters and format spec applied to a substitution.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Transform<'a> {
+    /// The names of the filters to apply, in order, separated by `|`.
+    filters: &'a str,
+    spec: Option<Spec>,
+}
+
+/// The directives which begin and end a raw section of a template.
+const RAW_START: &str = "${raw}";
+const RAW_END: &str = "${endraw}";
+
+/// Split a template into lines, removing common indentation and trailing
+/// whitespace, and find each substitution within it. If `args` is `None`,
+/// substitutions are not performed.
+///
+/// Text within a template between `${raw}` and `${endraw}` is kept exactly as
+/// written, and does not affect the common indentation.
+pub(crate) fn parse<'a>(
+    tmpl: &'a str,
+    args: Option<&[BuildArg]>,
+) -> Vec<Piece<'a>> {
+    // Come up with a size estimate. This should mean that we never need to
+    // re-allocate our backing buffer.
+    let mut estimate = count_char(tmpl, '\n') * 2 + 1;
+    if args.is_some() {
+        estimate += count_char(tmpl, '$') * 2;
+    }
+
+    // Split the template into normal text, each followed by an optional raw
+    // section.
+    let mut segments = Vec::new();
+    let mut rest = tmpl;
+    if let Some(args) = args {
+        for arg in args {
+            if arg.name == "raw" || arg.name == "endraw" {
+                panic!("`{}` is reserved for raw sections, so can't be used \
+                        as the name of a substitution", arg.name);
+            }
+        }
+
+        while let Some(start) = rest.find(RAW_START) {
+            let raw = &rest[start + RAW_START.len()..];
+            let end = raw.find(RAW_END)
+                .expect("`${raw}` without a matching `${endraw}`");
+            segments.push((&rest[..start], Some(&raw[..end])));
+            rest = &raw[end + RAW_END.len()..];
+        }
+    }
+    segments.push((rest, None));
+
+    // The first line of a segment after a raw section continues the line
+    // which the raw section ended on, so it doesn't affect indentation.
+    let indent = segments.iter().enumerate().map(|(idx, &(seg, _))| {
+        if idx == 0 {
+            min_indent(seg)
+        } else {
+            seg.find('\n').map_or(usize::MAX, |x| min_indent(&seg[x+1..]))
+        }
+    }).min().unwrap_or(usize::MAX);
+
+    let mut pieces = Vec::with_capacity(estimate);
+    for (idx, &(seg, raw)) in segments.iter().enumerate() {
+        parse_lines(seg, indent, idx != 0, raw.is_some(), args, &mut pieces);
+        match raw {
+            Some(raw) if !raw.is_empty() => pieces.push(Piece::Raw(raw)),
+            _ => {}
+        }
+    }
+
+    debug_assert!(estimate >= pieces.len());
+    pieces
+}
+
+/// Parse the lines of a segment of a template into `pieces`. `continued` is
+/// set if the segment starts part way through a line, and `unfinished` is set
+/// if the segment ends part way through a line.
+fn parse_lines<'a>(
+    seg: &'a str,
+    indent: usize,
+    continued: bool,
+    unfinished: bool,
+    args: Option<&[BuildArg]>,
+    pieces: &mut Vec<Piece<'a>>,
+) {
+    // NOTE: We use .split('\n') rather than .lines here because we want to
+    // handle the last newline correctly.
+    let mut lines = seg.split('\n').enumerate().peekable();
+    while let Some((idx, mut line)) = lines.next() {
+        if idx != 0 {
+            pieces.push(Piece::Nl);
+        }
+
+        // Remove any common indent prefix, and remove trailing whitespace.
+        if (idx != 0 || !continued) && line.len() >= indent {
+            line = &line[indent..];
+        }
+        if lines.peek().is_some() || !unfinished {
+            line = line.trim_end();
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(args) = args {
+            while let Some(point) = subst_point(line) {
+                line = point.after;
+                if !point.before.is_empty() {
+                    pieces.push(Piece::Text(point.before));
+                }
+                let idx = arg_index(point.name, args);
+                let transform = if point.filters.is_empty() &&
+                    point.spec.is_none()
+                {
+                    None
+                } else {
+                    Some(Transform {
+                        filters: point.filters,
+                        spec: point.spec.map(Spec::parse),
+                    })
+                };
+                pieces.push(Piece::Arg(point.name, idx, transform));
+            }
+        }
+
+        if !line.is_empty() {
+            pieces.push(Piece::Text(line));
+        }
+    }
+}
+
+/// Split a runtime string into lines, removing trailing whitespace, and, if
+/// `dedent` is set, the common indentation of its non-blank lines.
+pub(crate) fn parse_str(s: &str, dedent: bool) -> Vec<Piece<'_>> {
+    if dedent {
+        return parse(s, None);
+    }
+    let mut pieces = Vec::with_capacity(count_char(s, '\n') * 2 + 1);
+    parse_lines(s, 0, false, false, None, &mut pieces);
+    pieces
+}
+
+/// Convert a string into `Code`, removing trailing whitespace, and, if
+/// `dedent` is set, common indentation. `str_op` is used to create the op for
+/// each chunk of text.
+pub(crate) fn str_to_code<'a, F>(
+    tmpl: &'a str,
+    dedent: bool,
+    mut str_op: F,
+) -> Code
+where
+    F: FnMut(&'a str) -> Op
+{
+    let ops = parse_str(tmpl, dedent).into_iter().map(|piece| match piece {
+        Piece::Nl => Op::Nl,
+        Piece::Text(s) => str_op(s),
+        Piece::Arg(..) | Piece::Raw(..) => {
+            unreachable!("directive without arguments")
+        }
+    }).collect();
+    Code::from_ops(ops)
+}
+
+/// A template from a `code!` invocation which has been parsed, and is ready to
+/// have arguments bound to it.
+#[derive(Debug)]
+struct Template {
+    tmpl: &'static str,
+    pieces: Vec<Piece<'static>>,
+}
+
+impl Template {
+    fn new(tmpl: &'static str, args: &[BuildArg]) -> Self {
+        Template {
+            tmpl,
+            pieces: parse(tmpl, Some(args)),
+        }
+    }
+
+    /// Bind `args` to the template, appending the resulting ops to `ops`.
+    /// `text` is the text of the arena which `ops` belongs to, if any, and
+    /// `filters` are used to transform substitutions.
+    fn bind(
+        &self,
+        sourceloc: &'static SourceLoc,
+        args: &mut [BuildArg],
+        ops: &mut Vec<Op>,
+        text: &str,
+        filters: &Filters,
+    ) {
+        ops.reserve(self.pieces.len() + 1);
+        ops.push(Op::SourceLoc(sourceloc));
+        for piece in &self.pieces {
+            match *piece {
+                Piece::Nl => ops.push(Op::Nl),
+                Piece::Text(s) => ops.push(Op::Lit(s)),
+                Piece::Raw(s) => ops.push(Op::RawLit(s)),
+                Piece::Arg(name, idx, Some(transform)) => {
+                    debug_assert_eq!(arg_index(name, args), idx);
+                    let arg = &args[idx];
+                    let op = transform_arg(arg, transform, ops, text, filters)
+                        .unwrap_or_else(|| panic!(
+                            "Format spec used with substitution {}, which \
+                             isn't a number or a single line of text",
+                            name,
+                        ));
+                    ops.push(op);
+                }
+                Piece::Arg(name, idx, None) => {
+                    // The arguments to a given `code!` invocation are always
+                    // the same, so the index we cached must still be correct.
+                    debug_assert_eq!(arg_index(name, args), idx);
+                    let arg = &mut args[idx];
+                    if let Some(op) = arg.op.take() {
+                        arg.index = ops.len();
+                        ops.push(op);
+                    } else {
+                        let off = ops.len() - arg.index;
+                        ops.push(Op::InnerRef(off));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Get the text of an op which is a single line of text.
+fn op_text<'a>(op: &'a Op, text: &'a str) -> Option<&'a str> {
+    match *op {
+        Op::Lit(s) => Some(s),
+        Op::Blob(ref s) => Some(s),
+        Op::ArenaText(start, end) => Some(&text[start..end]),
+        Op::EmitOnce(ref def) => Some(&def.key),
+        Op::Inner(ref inner) => {
+            let mut ops = inner.iter()
+                .filter(|op| !matches!(op, Op::SourceLoc(_)));
+            match (ops.next(), ops.next()) {
+                (None, _) => Some(""),
+                (Some(op), None) => op_text(op, text),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Get the text of an argument's op, rendering it if it isn't a single chunk
+/// of text, and whether it contains raw text. `ops` and `text` are the
+/// contents of the arena, if any.
+///
+/// Like a substituted `EmitOnce`, only the keys of any definitions which the
+/// argument references are included in its text.
+fn arg_text<'a>(
+    op: &'a Op,
+    ops: &[Op],
+    text: &'a str,
+) -> (Cow<'a, str>, bool) {
+    match op_text(op, text) {
+        Some(s) => (s.into(), false),
+        None => {
+            let ops = export(text, ops, slice::from_ref(op));
+            (render_text(&ops).into(), has_raw(&ops))
+        }
+    }
+}
+
+/// Check whether `ops`, which must not be in an arena, contain raw text.
+fn has_raw(ops: &[Op]) -> bool {
+    ops.iter().any(|op| match *op {
+        Op::RawLit(..) | Op::RawBlob(..) => true,
+        Op::Inner(ref inner) |
+        Op::Indented(_, ref inner) |
+        Op::Dedented(ref inner) |
+        Op::BlankLines(_, ref inner) => has_raw(inner),
+        _ => false,
+    })
+}
+
+/// Apply a transform to an argument, returning `None` if a format spec can't
+/// be applied to it. The argument's op may already have been bound, in which
+/// case it is found in `ops`.
+fn transform_arg(
+    arg: &BuildArg,
+    transform: Transform,
+    ops: &[Op],
+    text: &str,
+    filters: &Filters,
+) -> Option<Op> {
+    if let (Some(value), Some(spec), "") =
+        (arg.value, transform.spec, transform.filters)
+    {
+        return Some(Op::Blob(spec.number(value).into_boxed_str()));
+    }
+
+    let op = arg.op.as_ref().unwrap_or(&ops[arg.index]);
+    let (mut s, mut raw) = arg_text(op, ops, text);
+    let mut code: Option<Code> = None;
+    if !transform.filters.is_empty() {
+        for name in transform.filters.split('|') {
+            if let Some(code) = code.take() {
+                s = render_text(&code.ops).into();
+                raw = has_raw(&code.ops);
+            }
+            match filters.apply(name, &s) {
+                Filtered::Text(filtered) => s = filtered.into(),
+                Filtered::Code(filtered) => code = Some(filtered),
+            }
+        }
+    }
+    if let Some(code) = code {
+        match transform.spec {
+            Some(_) => s = render_text(&code.ops).into(),
+            None => return Some(Op::Inner(code.ops)),
+        }
+    }
+    // NOTE: Text produced from raw text is emitted raw, as re-indenting it
+    // would change the contents of literals such as quoted strings.
+    match transform.spec {
+        Some(_) if s.contains('\n') => None,
+        Some(spec) => Some(Op::Blob(spec.text(&s).into_boxed_str())),
+        None if raw && s.contains('\n') => Some(Op::RawBlob(s.into())),
+        None => Some(text_op(&s)),
+    }
+}
+
+// Not a public API
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct TemplateCache {
+    enabled: bool,
+    template: AtomicPtr<Template>,
+}
+
+impl TemplateCache {
+    // Not a public API
+    #[doc(hidden)]
+    pub const fn new() -> Self {
+        TemplateCache {
+            enabled: true,
+            template: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    // Not a public API - used to measure the benefit of caching.
+    #[doc(hidden)]
+    pub const fn disabled() -> Self {
+        TemplateCache {
+            enabled: false,
+            template: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Build the ops for `tmpl` into `ops`, re-using the parsed template from
+    /// a previous call if one is available.
+    pub(crate) fn build(
+        &self,
+        tmpl: &'static str,
+        sourceloc: &'static SourceLoc,
+        args: &mut [BuildArg],
+        ops: &mut Vec<Op>,
+        text: &str,
+        filters: &Filters,
+    ) {
+        // NOTE: The template is always the same string literal when invoked
+        // through `code!`, but it is an arbitrary expression, so we double
+        // check that it hasn't changed before using the cached version.
+        let cached = self.template.load(Ordering::Acquire);
+        if let Some(cached) = unsafe { cached.as_ref() } {
+            if ptr::eq(cached.tmpl, tmpl) {
+                return cached.bind(sourceloc, args, ops, text, filters);
+            }
+        }
+
+        let template = Box::new(Template::new(tmpl, args));
+        template.bind(sourceloc, args, ops, text, filters);
+        if self.enabled {
+            // If another thread got there first, or the template has changed,
+            // we just drop our copy.
+            let template = Box::into_raw(template);
+            let installed = self.template.compare_exchange(
+                ptr::null_mut(),
+                template,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+            if installed.is_err() {
+                drop(unsafe { Box::from_raw(template) });
+            }
+        }
+    }
+}
+
+impl Drop for TemplateCache {
+    fn drop(&mut self) {
+        let template = *self.template.get_mut();
+        if !template.is_null() {
+            drop(unsafe { Box::from_raw(template) });
+        }
+    }
+}
+
+#[test]
+fn template_cache() {
+    static LOC: SourceLoc = SourceLoc::new(0, 0, "");
+
+    for i in 0..3 {
+        let code = Code::build("$a + $b * $a", &LOC, &mut [
+            BuildArg::new("a", i),
+            BuildArg::new("b", "x"),
+        ]);
+        assert_eq!(code.to_string(), format!("{} + x * {}", i, i));
+        assert!(!LOC.template.template.load(Ordering::Acquire).is_null());
+    }
+
+    // A different template at the same location is parsed from scratch.
+    let code = Code::build("$b", &LOC, &mut [BuildArg::new("b", "y")]);
+    assert_eq!(code.to_string(), "y");
+}
+
+#[test]
+fn argument_forms() {
+    use alloc::string::ToString;
+
+    let x = 1;
+    let y = "b";
+    let res = code!("$x $y $z $0 $1 $2 $3", x, y, x + 1, z: "c");
+    assert_eq!(res.to_string(), "1 b c 1 b 2 c");
+    let res = code!("$x$y", x: x, y,);
+    assert_eq!(res.to_string(), "1b");
+
+    let arena = ::CodeArena::new();
+    let res = code_in!(&arena, "$0($x, $2)", "f", x, Some(y));
+    assert_eq!(res.to_string(), "f(1, b)");
+}
+
+#[test]
+fn formatted_args() {
+    let name = "id";
+    let res = code!("
+        ${name:<6}= ${val:#x}; // $val
+        ${0:-^8}= ${2:08.3};",
+        name,
+        val: -2i8,
+        2.5f32,
+    );
+    assert_eq!(res.to_string(), "id    = 0xfe; // -2\n---id---= 0002.500;");
+
+    // Formatted arguments are also available within arenas, and a `Code`
+    // argument can be formatted when it is a single line.
+    let arena = ::CodeArena::new();
+    let res = code_in!(&arena, "[${0:>3}|${1:3}|$1]", name, code!("x"));
+    assert_eq!(res.to_string(), "[ id|x  |x]");
+}
+
+#[test]
+#[should_panic(expected = "No argument provided for substitution 1")]
+fn missing_positional() {
+    code!("$0 $1", "a");
+}
+
+#[test]
+#[should_panic(expected = "`raw` is reserved for raw sections")]
+fn reserved_names() {
+    code!("${raw}", raw: 1);
+}
+
+#[test]
+fn raw_sections() {
+    use alloc::string::ToString;
+    use Raw;
+
+    // Raw sections don't affect indentation, or have their whitespace
+    // trimmed, and may start and end part way through a line.
+    let code = code!("
+        {
+            a = ${raw}\"x  \n   \n\n}\"${endraw};
+            b;
+        }",
+    );
+    assert_eq!(code.to_string(), "{\n    a = \"x  \n   \n\n}\";\n    b;\n}");
+
+    let code = code!("$a$b", a: Raw("  \n"), b: Raw("\n\nx"));
+    assert_eq!(code.to_string(), "  \n\n\nx");
+}
@@ -0,0 +1,375 @@
+//! This module provides `Visitor`, for inspecting the structure of `Code`, and
+//! `Code::map_text`, for rewriting its text.
+
+use {Code, Op, SourceLoc};
+use once::OnceDef;
+
+use alloc::borrow::Cow;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+
+/// A visitor over the structure of a [`Code`] object, used with
+/// [`Code::visit`].
+///
+/// Each method has an empty default implementation, so implementors only need
+/// to handle the parts of the code they are interested in.
+///
+/// The visitor sees the code as it was built, before indentation is applied to
+/// nested fragments, including those created by `Code::indented` and
+/// `Code::dedent`. A fragment which was substituted in more than one place is
+/// visited at each of them.
+///
+/// The definition of an [`EmitOnce`] is visited as a nested fragment the first
+/// time its key is referenced, after a call to [`Visitor::emit_once`]. Each
+/// reference is then visited as the text of its key.
+///
+/// [`Code`]: struct.Code.html
+/// [`Code::visit`]: struct.Code.html#method.visit
+/// [`EmitOnce`]: struct.EmitOnce.html
+/// [`Visitor::emit_once`]: #method.emit_once
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate cghelper;
+/// # use cghelper::{SourceLoc, Visitor};
+/// # fn main() {
+/// // Count the newlines which came from each source location.
+/// #[derive(Default)]
+/// struct LineCounter {
+///     stack: Vec<Option<&'static SourceLoc>>,
+///     counts: Vec<(&'static SourceLoc, usize)>,
+/// }
+///
+/// impl Visitor for LineCounter {
+///     fn source_loc(&mut self, loc: &'static SourceLoc) {
+///         *self.stack.last_mut().unwrap() = Some(loc);
+///     }
+///     fn newline(&mut self) {
+///         let loc = self.stack.last().unwrap().unwrap();
+///         match self.counts.iter_mut().find(|&&mut (l, _)| l == loc) {
+///             Some(entry) => entry.1 += 1,
+///             None => self.counts.push((loc, 1)),
+///         }
+///     }
+///     fn enter(&mut self) { self.stack.push(None); }
+///     fn exit(&mut self) { self.stack.pop(); }
+/// }
+///
+/// let inner = code!("a;\nb;\n");
+/// let outer = code!("$inner\nc;", inner: inner);
+///
+/// let mut counter = LineCounter::default();
+/// counter.enter();
+/// outer.visit(&mut counter);
+/// let counts: Vec<_> = counter.counts.iter().map(|&(_, n)| n).collect();
+/// assert_eq!(counts, [2, 1]);
+/// # }
+/// ```
+pub trait Visitor {
+    /// Called for each chunk of text. The text never contains a newline.
+    fn text(&mut self, text: &str) {
+        let _ = text;
+    }
+
+    /// Called for each newline.
+    fn newline(&mut self) {}
+
+    /// Called when the code which follows, up to the end of the current
+    /// fragment, was produced by the `code!` invocation at `loc`.
+    fn source_loc(&mut self, loc: &'static SourceLoc) {
+        let _ = loc;
+    }
+
+    /// Called before visiting a nested fragment, such as an argument
+    /// substituted into a `code!` template.
+    fn enter(&mut self) {}
+
+    /// Called after visiting a nested fragment.
+    fn exit(&mut self) {}
+
+    /// Called before visiting the definition of an `EmitOnce` with the given
+    /// key, which is visited as a nested fragment.
+    fn emit_once(&mut self, key: &str) {
+        let _ = key;
+    }
+}
+
+/// Walk `ops`, recording the keys of the `EmitOnce` definitions which have
+/// been visited in `seen`.
+fn walk<'a, V>(ops: &'a [Op], visitor: &mut V, seen: &mut Vec<&'a str>)
+where
+    V: Visitor + ?Sized
+{
+    for idx in 0..ops.len() {
+        walk_op(ops, idx, visitor, seen);
+    }
+}
+
+fn walk_op<'a, V>(
+    ops: &'a [Op],
+    idx: usize,
+    visitor: &mut V,
+    seen: &mut Vec<&'a str>,
+)
+where
+    V: Visitor + ?Sized
+{
+    match ops[idx] {
+        Op::Nl => visitor.newline(),
+        Op::Lit(s) => visitor.text(s),
+        Op::Blob(ref s) => visitor.text(s),
+        Op::RawLit(s) => walk_raw(s, visitor),
+        Op::RawBlob(ref s) => walk_raw(s, visitor),
+        Op::Inner(ref inner) |
+        Op::Indented(_, ref inner) |
+        Op::Dedented(ref inner) |
+        Op::BlankLines(_, ref inner) => {
+            visitor.enter();
+            walk(inner, visitor, seen);
+            visitor.exit();
+        }
+        Op::InnerRef(back) => walk_op(ops, idx - back, visitor, seen),
+        Op::SourceLoc(loc) => visitor.source_loc(loc),
+        Op::EmitOnce(ref def) => {
+            // Like the display engine, only the first definition for each key
+            // is used.
+            if !seen.contains(&&*def.key) {
+                seen.push(&def.key);
+                visitor.emit_once(&def.key);
+                visitor.enter();
+                walk(&def.code.ops, visitor, seen);
+                visitor.exit();
+            }
+            visitor.text(&def.key);
+        }
+        Op::EmitAnchor => {}
+        Op::ArenaText(..) | Op::ArenaInner(..) => {
+            unreachable!("arena ops outside of an arena")
+        }
+    }
+}
+
+fn walk_raw<V: Visitor + ?Sized>(s: &str, visitor: &mut V) {
+    for (idx, line) in s.split('\n').enumerate() {
+        if idx != 0 {
+            visitor.newline();
+        }
+        if !line.is_empty() {
+            visitor.text(line);
+        }
+    }
+}
+
+/// Create the op for the replacement text of a single chunk. The replacement
+/// may contain newlines, in which case it is split into a nested fragment so
+/// that it still occupies a single op.
+pub(crate) fn text_op(s: &str) -> Op {
+    if !s.contains('\n') {
+        return Op::Blob(s.into());
+    }
+
+    let mut ops = Vec::new();
+    for (idx, line) in s.split('\n').enumerate() {
+        if idx != 0 {
+            ops.push(Op::Nl);
+        }
+        if !line.is_empty() {
+            ops.push(Op::Blob(line.into()));
+        }
+    }
+    Op::Inner(Arc::new(ops))
+}
+
+/// The `EmitOnce` definitions which have been mapped, and their replacements,
+/// if they or their keys changed.
+type MappedDefs = Vec<(*const OnceDef, Option<Arc<OnceDef>>)>;
+
+/// Map the text of `ops`, returning `None` if none of it changed.
+fn map_ops<F>(ops: &[Op], f: &mut F, once: &mut MappedDefs) -> Option<Vec<Op>>
+where
+    F: FnMut(&str) -> Cow<'_, str>
+{
+    let mut mapped: Option<Vec<Op>> = None;
+    for (idx, op) in ops.iter().enumerate() {
+        let new_op = match *op {
+            Op::Lit(s) => map_str(s, f),
+            Op::Blob(ref s) => map_str(s, f),
+            Op::Inner(ref inner) => {
+                map_ops(inner, f, once).map(|ops| Op::Inner(Arc::new(ops)))
+            }
+            Op::Indented(n, ref inner) => {
+                map_ops(inner, f, once)
+                    .map(|ops| Op::Indented(n, Arc::new(ops)))
+            }
+            Op::Dedented(ref inner) => {
+                map_ops(inner, f, once).map(|ops| Op::Dedented(Arc::new(ops)))
+            }
+            Op::BlankLines(policy, ref inner) => {
+                map_ops(inner, f, once)
+                    .map(|ops| Op::BlankLines(policy, Arc::new(ops)))
+            }
+            Op::EmitOnce(ref def) => map_once(def, f, once).map(Op::EmitOnce),
+            // NOTE: Each op is mapped to exactly one op, so the offsets in
+            // `InnerRef`s remain valid, and refer to the mapped fragment.
+            _ => None,
+        };
+
+        match (new_op, &mut mapped) {
+            (Some(new_op), &mut Some(ref mut mapped)) => mapped.push(new_op),
+            (Some(new_op), mapped) => {
+                let mut ops = ops[..idx].to_vec();
+                ops.push(new_op);
+                *mapped = Some(ops);
+            }
+            (None, &mut Some(ref mut mapped)) => mapped.push(op.clone()),
+            (None, &mut None) => {}
+        }
+    }
+    mapped
+}
+
+/// Map the text of an `EmitOnce` definition, re-using the result if the same
+/// definition has already been mapped.
+fn map_once<F>(
+    def: &Arc<OnceDef>,
+    f: &mut F,
+    once: &mut MappedDefs,
+) -> Option<Arc<OnceDef>>
+where
+    F: FnMut(&str) -> Cow<'_, str>
+{
+    let ptr: *const OnceDef = &**def;
+    if let Some((_, mapped)) = once.iter().find(|&&(p, _)| p == ptr) {
+        return mapped.clone();
+    }
+    // The key is rendered as text wherever the definition is referenced, so
+    // it is rewritten too.
+    let key = match f(&def.key) {
+        Cow::Borrowed(key) if ptr::eq(key, &*def.key) => None,
+        key => {
+            assert!(!key.contains('\n'), "EmitOnce key mapped to a newline");
+            Some(key.into_owned().into_boxed_str())
+        }
+    };
+    let ops = map_ops(&def.code.ops, f, once);
+    let mapped = if key.is_some() || ops.is_some() {
+        Some(Arc::new(OnceDef {
+            key: key.unwrap_or_else(|| def.key.clone()),
+            code: ops.map_or_else(|| def.code.clone(), Code::from_ops),
+        }))
+    } else {
+        None
+    };
+    once.push((ptr, mapped.clone()));
+    mapped
+}
+
+fn map_str<F>(s: &str, f: &mut F) -> Option<Op>
+where
+    F: FnMut(&str) -> Cow<'_, str>
+{
+    match f(s) {
+        Cow::Borrowed(new) if ptr::eq(new, s) => None,
+        new => Some(text_op(&new)),
+    }
+}
+
+impl Code {
+    /// Visit the structure of this code with the given [`Visitor`].
+    ///
+    /// [`Visitor`]: trait.Visitor.html
+    pub fn visit<V: Visitor + ?Sized>(&self, visitor: &mut V) {
+        walk(&self.ops, visitor, &mut Vec::new());
+    }
+
+    /// Create a copy of this code with each chunk of text rewritten by `f`.
+    ///
+    /// The structure and source locations of the code are preserved. `f` is
+    /// called once for each chunk of text, which never contains a newline,
+    /// and a fragment which was substituted in more than one place is only
+    /// rewritten once. If `f` returns the text it was given, the chunk is left
+    /// unchanged. If the new text contains newlines, following lines are
+    /// indented in the same way as a substituted fragment. Raw text is not
+    /// rewritten.
+    ///
+    /// The definitions of `EmitOnce` references are rewritten too, each only
+    /// once, and their keys are rewritten like any other chunk of text, so
+    /// references to a renamed definition use its new name.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use] extern crate cghelper;
+    /// # fn main() {
+    /// let call = code!("old($x)", x: "old");
+    /// let res = code!("$call;\n$call;", call: call);
+    /// let renamed = res.map_text(|s| s.replace("old", "new").into());
+    /// assert_eq!(renamed.to_string(), "new(new);\nnew(new);");
+    /// # }
+    /// ```
+    pub fn map_text<F>(&self, mut f: F) -> Code
+    where
+        F: FnMut(&str) -> Cow<'_, str>
+    {
+        match map_ops(&self.ops, &mut f, &mut Vec::new()) {
+            Some(ops) => Code::from_ops(ops),
+            None => self.clone(),
+        }
+    }
+}
+
+#[test]
+fn visit_test() {
+    use alloc::string::{String, ToString};
+
+    #[derive(Default)]
+    struct Dump(String);
+    impl Visitor for Dump {
+        fn text(&mut self, text: &str) { self.0.push_str(text); }
+        fn newline(&mut self) { self.0.push('\n'); }
+        fn source_loc(&mut self, _: &'static SourceLoc) { self.0.push('@'); }
+        fn enter(&mut self) { self.0.push('['); }
+        fn exit(&mut self) { self.0.push(']'); }
+    }
+
+    let inner = code!("x");
+    let res = code!("
+        $a + $a
+        // $b",
+        a: inner,
+        b: "comment",
+    );
+    let mut dump = Dump::default();
+    res.visit(&mut dump);
+    assert_eq!(dump.0, "@\n[@x] + [@x]\n// [comment]");
+
+    // Strip comment lines, leaving the rest of the code alone.
+    let stripped = res.map_text(|s| {
+        if s.starts_with("//") { "".into() } else { s.into() }
+    });
+    assert_eq!(stripped.to_string(), "x + x\ncomment");
+    let unchanged = res.map_text(|s| s.into());
+    assert!(Arc::ptr_eq(&unchanged.ops, &res.ops));
+
+    let multiline = code!("x = $a", a: "a");
+    let multiline = multiline.map_text(|s| s.replace("a", "b\nc").into());
+    assert_eq!(multiline.to_string(), "x = b\n    c");
+
+    // Definitions are visited and mapped once, wherever they are referenced.
+    let helper = ::EmitOnce::new("h", code!("// helper\nvoid h();"));
+    let res = code!("$a; $b;", a: helper.clone(), b: helper);
+    let mut dump = Dump::default();
+    res.visit(&mut dump);
+    assert_eq!(dump.0, "@[[@// helper\nvoid h();]h]; [h];");
+    let stripped = res.map_text(|s| {
+        if s.starts_with("//") { "".into() } else { s.into() }
+    });
+    assert_eq!(stripped.to_string(), "void h();\n\nh; h;");
+
+    // Keys are rewritten along with the definitions.
+    let old = ::EmitOnce::new("old", code!("int old;"));
+    let res = code!("$old", old).map_text(|s| s.replace("old", "new").into());
+    assert_eq!(res.to_string(), "int new;\n\nnew");
+}